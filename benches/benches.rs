@@ -7,7 +7,7 @@ mod engine {
     use rand::Rng;
     use tempfile::TempDir;
 
-    use kvs::engine::KvStore;
+    use kvs::engine::{KvStore, SledAdapter};
     use kvs::*;
 
     lazy_static! {
@@ -87,26 +87,455 @@ mod engine {
 }
 
 mod thread_pool {
-    use criterion::Criterion;
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+
+    use criterion::measurement::WallTime;
+    use criterion::{BenchmarkGroup, BenchmarkId, Criterion};
     use tempfile::TempDir;
 
-    use kvs::thread_pool::ThreadPool;
-    use kvs::{KvServer, SledAdapter};
+    use kvs::engine::{KvStore, KvStoreConfig, SledAdapter};
+    use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+    use kvs::{FlushPolicy, KvClient, KvServer, KvsEngine, Result, ServerHandle};
 
-    pub fn suite_main(ct: &mut Criterion) {
-        let _group = ct.benchmark_group("Write_test");
+    const CLIENTS: usize = 8;
+    const REQUESTS_PER_CLIENT: usize = 20;
+
+    // The pool is not `Send`, so the server is built on the thread that runs it.
+    fn serve<P: ThreadPool, E: KvsEngine>(
+        engine: E,
+        addr: &str,
+        flush_policy: FlushPolicy,
+    ) -> (ServerHandle, JoinHandle<Result<()>>) {
+        let (sender, receiver) = mpsc::channel();
+        let addr = addr.to_owned();
+        let server = thread::spawn(move || {
+            let server = KvServer::new(engine, P::new(num_cpus::get() as u32).unwrap(), addr)
+                .unwrap()
+                .with_flush_policy(flush_policy);
+            sender.send(server.handle().unwrap()).unwrap();
+            server.run()
+        });
+        (receiver.recv().unwrap(), server)
+    }
+
+    // Every client connects, sends its requests one by one, then disconnects.
+    fn drive_clients(addr: &str, write: bool) {
+        let clients: Vec<_> = (0..CLIENTS)
+            .map(|client_id| {
+                let addr = addr.to_owned();
+                thread::spawn(move || {
+                    let mut client = KvClient::connect(addr).unwrap();
+                    for i in 0..REQUESTS_PER_CLIENT {
+                        let key = format!("key{}-{}", client_id, i);
+                        if write {
+                            client.set(key, "value".to_owned()).unwrap();
+                        } else {
+                            client.get(key).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+
+    fn bench_pool<P: ThreadPool>(group: &mut BenchmarkGroup<WallTime>, name: &str, addr: &str) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = KvStore::open(temp_dir.path()).unwrap();
+        let (handle, server) = serve::<P, _>(engine, addr, FlushPolicy::default());
+        group.bench_function(BenchmarkId::new("write", name), |b| {
+            b.iter(|| drive_clients(addr, true))
+        });
+        group.bench_function(BenchmarkId::new("read", name), |b| {
+            b.iter(|| drive_clients(addr, false))
+        });
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    // Gets of small values found in the store, one client asking one after the other,
+    // so the time goes to the request path rather than the engine. With the value cache
+    // the values are encoded from it without a copy.
+    fn bench_get_hits(
+        group: &mut BenchmarkGroup<WallTime>,
+        name: &str,
+        config: KvStoreConfig,
+        addr: &str,
+    ) {
+        const KEYS: usize = 1000;
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+        for i in 0..KEYS {
+            engine.set(&format!("key{}", i), "value").unwrap();
+        }
+        let (handle, server) =
+            serve::<SharedQueueThreadPool, _>(engine, addr, FlushPolicy::default());
+        let mut client = KvClient::connect(addr).unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for i in 0..KEYS {
+                    client.get(format!("key{}", i)).unwrap().unwrap();
+                }
+            })
+        });
+        drop(client);
+        handle.shutdown();
+        server.join().unwrap().unwrap();
     }
 
-    fn write_queued_kvstore<T: ThreadPool>(pool: T) {
+    // Sled writes over the network, flushing after each one or in batches.
+    fn bench_sled_flush(
+        group: &mut BenchmarkGroup<WallTime>,
+        name: &str,
+        flush_policy: FlushPolicy,
+        async_flush: bool,
+        addr: &str,
+    ) {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let _server = KvServer::new(
-            SledAdapter::open(temp_dir.path()).unwrap(),
-            pool,
-            format!("127.0.0.1:8888"),
+        let engine = SledAdapter::open(temp_dir.path())
+            .unwrap()
+            .with_async_flush(async_flush);
+        let (handle, server) = serve::<RayonThreadPool, _>(engine, addr, flush_policy);
+        group.bench_function(BenchmarkId::new("sled-write", name), |b| {
+            b.iter(|| drive_clients(addr, true))
+        });
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    pub fn suite_main(ct: &mut Criterion) {
+        let mut group = ct.benchmark_group("Networked");
+        group.sample_size(10);
+        bench_pool::<NaiveThreadPool>(&mut group, "naive", "127.0.0.1:4200");
+        bench_pool::<SharedQueueThreadPool>(&mut group, "shared-queue", "127.0.0.1:4201");
+        bench_pool::<RayonThreadPool>(&mut group, "rayon", "127.0.0.1:4202");
+        bench_sled_flush(
+            &mut group,
+            "flush-every-write",
+            FlushPolicy::EveryWrite,
+            false,
+            "127.0.0.1:4203",
         );
+        bench_sled_flush(
+            &mut group,
+            "flush-every-64",
+            FlushPolicy::EveryN(64),
+            false,
+            "127.0.0.1:4204",
+        );
+        bench_sled_flush(
+            &mut group,
+            "async-flush",
+            FlushPolicy::EveryWrite,
+            true,
+            "127.0.0.1:4205",
+        );
+        bench_get_hits(
+            &mut group,
+            "get-hits",
+            KvStoreConfig::default(),
+            "127.0.0.1:4206",
+        );
+        let cached = KvStoreConfig {
+            value_cache_size: Some(1 << 20),
+            ..Default::default()
+        };
+        bench_get_hits(&mut group, "get-hits-cached", cached, "127.0.0.1:4207");
+        group.finish();
+    }
+}
+
+mod compaction {
+    use std::sync::{Arc, Mutex};
+
+    use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+    use tempfile::TempDir;
+
+    use kvs::engine::{CompactionStats, KvStore, KvStoreConfig};
+    use kvs::KvsEngine;
+
+    const VALUE_SIZE: usize = 64 * 1024;
+    const KEYS: usize = 32;
+    const ROUNDS: usize = 4;
+
+    // Every key written `rounds` times, no compaction runs before the measured one.
+    fn filled_store(
+        config: &KvStoreConfig,
+        keys: usize,
+        value_size: usize,
+        rounds: usize,
+    ) -> (TempDir, KvStore) {
+        let temp_dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            compaction_threshold: Some(usize::MAX),
+            ..config.clone()
+        };
+        let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+        let value = "v".repeat(value_size);
+        for _ in 0..rounds {
+            for i in 0..keys {
+                store.set(&format!("key{}", i), &value).unwrap();
+            }
+        }
+        (temp_dir, store)
+    }
+
+    fn compaction_stats(
+        config: &KvStoreConfig,
+        keys: usize,
+        value_size: usize,
+        rounds: usize,
+    ) -> CompactionStats {
+        let (_temp_dir, store) = filled_store(config, keys, value_size, rounds);
+        let stats = Arc::new(Mutex::new(None));
+        let stats_ref = stats.clone();
+        store
+            .on_compaction(Box::new(move |s| *stats_ref.lock().unwrap() = Some(s)))
+            .unwrap();
+        store.compact().unwrap();
+        let stats = stats.lock().unwrap().take().unwrap();
+        stats
+    }
+
+    fn bench_compact(
+        ct: &mut Criterion,
+        group_name: &str,
+        cases: &[(String, KvStoreConfig, usize, usize, usize)],
+    ) {
+        let mut group = ct.benchmark_group(group_name);
+        group.sample_size(10);
+        for (name, config, keys, value_size, rounds) in cases.iter() {
+            // Reported as the bytes compacted per second.
+            let stats = compaction_stats(config, *keys, *value_size, *rounds);
+            group.throughput(Throughput::Bytes(stats.bytes_before));
+            group.bench_function(BenchmarkId::from_parameter(name), |b| {
+                // A fresh store each time, compacting twice would find no garbage.
+                b.iter_batched(
+                    || filled_store(config, *keys, *value_size, *rounds),
+                    |(temp_dir, store)| {
+                        store.compact().unwrap();
+                        (temp_dir, store)
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+        group.finish();
+    }
+
+    pub fn suite_main(ct: &mut Criterion) {
+        let mut cases = Vec::new();
+        for &(keys, value_size) in [(1000, 100), (1000, 4096), (10000, 100)].iter() {
+            cases.push((
+                format!("{}keys-{}B", keys, value_size),
+                KvStoreConfig::default(),
+                keys,
+                value_size,
+                ROUNDS,
+            ));
+        }
+        bench_compact(ct, "Compaction", &cases);
 
-        for _ in 0..num_cpus::get() {}
+        let value_log = KvStoreConfig {
+            value_log_threshold: Some(4096),
+            ..Default::default()
+        };
+        let cases = [
+            (
+                "inline".to_owned(),
+                KvStoreConfig::default(),
+                KEYS,
+                VALUE_SIZE,
+                2,
+            ),
+            ("value-log".to_owned(), value_log, KEYS, VALUE_SIZE, 2),
+        ];
+        bench_compact(ct, "Compaction 64KB values", &cases);
+    }
+}
+
+mod large_reads {
+    use criterion::Criterion;
+    use tempfile::TempDir;
+
+    use kvs::engine::{KvStore, KvStoreConfig};
+    use kvs::KvsEngine;
+
+    const VALUE_SIZE: usize = 1024 * 1024;
+    const KEYS: usize = 16;
+
+    pub fn suite_main(ct: &mut Criterion) {
+        let mut group = ct.benchmark_group("Read 1MB values");
+        group.sample_size(10);
+        let configs = [
+            ("default-buffer", KvStoreConfig::default()),
+            (
+                "1MB-buffer",
+                KvStoreConfig {
+                    read_buffer_size: Some(VALUE_SIZE + 64),
+                    ..Default::default()
+                },
+            ),
+        ];
+        let value = "v".repeat(VALUE_SIZE);
+        for (name, config) in configs.iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open_with_config(temp_dir.path(), config.clone()).unwrap();
+            for i in 0..KEYS {
+                store.set(&format!("key{}", i), &value).unwrap();
+            }
+            group.bench_function(*name, |b| {
+                b.iter(|| {
+                    for i in 0..KEYS {
+                        assert_eq!(
+                            store.get(&format!("key{}", i)).unwrap().unwrap().len(),
+                            VALUE_SIZE
+                        );
+                    }
+                })
+            });
+        }
+        group.finish();
+    }
+}
+mod hot_key {
+    use criterion::Criterion;
+    use tempfile::TempDir;
+
+    use kvs::engine::{KvStore, KvStoreConfig};
+    use kvs::KvsEngine;
+
+    const VALUE_SIZE: usize = 4096;
+
+    pub fn suite_main(ct: &mut Criterion) {
+        let mut group = ct.benchmark_group("Read a hot key");
+        let configs = [
+            ("no-cache", KvStoreConfig::default()),
+            (
+                "value-cache",
+                KvStoreConfig {
+                    value_cache_size: Some(1024 * 1024),
+                    ..Default::default()
+                },
+            ),
+        ];
+        let value = "v".repeat(VALUE_SIZE);
+        for (name, config) in configs.iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open_with_config(temp_dir.path(), config.clone()).unwrap();
+            for i in 0..1000 {
+                store.set(&format!("key{}", i), &value).unwrap();
+            }
+            group.bench_function(*name, |b| {
+                b.iter(|| {
+                    assert_eq!(
+                        store.get_shared("key500").unwrap().unwrap().len(),
+                        VALUE_SIZE
+                    )
+                })
+            });
+        }
+        group.finish();
+    }
+}
+mod open {
+    use criterion::Criterion;
+    use tempfile::TempDir;
+
+    use kvs::engine::{DumpFormat, KvStore, KvStoreConfig, WriteOp};
+    use kvs::KvsEngine;
+
+    const KEYS: usize = 1_000_000;
+    const BATCH: usize = 10_000;
+
+    pub fn suite_main(ct: &mut Criterion) {
+        let mut group = ct.benchmark_group("Open a store of 1M keys");
+        group.sample_size(10);
+        let configs = [
+            ("json", DumpFormat::Json, false),
+            ("bincode", DumpFormat::Bincode, false),
+            ("mmap-bincode", DumpFormat::Bincode, true),
+        ];
+        for (name, dump_format, mmap_dump) in configs.iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let config = KvStoreConfig {
+                dump_format: *dump_format,
+                ..Default::default()
+            };
+            let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            for batch in 0..KEYS / BATCH {
+                let ops = (batch * BATCH..(batch + 1) * BATCH)
+                    .map(|i| WriteOp::Set {
+                        key: format!("key{:07}", i),
+                        value: "value".to_owned(),
+                    })
+                    .collect();
+                store.transaction(ops).unwrap();
+            }
+            store.close().unwrap();
+            // Read-only so closing doesn't write the dump again.
+            let config = KvStoreConfig {
+                read_only: true,
+                mmap_dump: *mmap_dump,
+                ..Default::default()
+            };
+            group.bench_function(*name, |b| {
+                b.iter(|| KvStore::open_with_config(temp_dir.path(), config.clone()).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+mod defragment {
+    use criterion::Criterion;
+    use rand::seq::SliceRandom;
+    use tempfile::TempDir;
+
+    use kvs::engine::{KvStore, KvStoreConfig};
+    use kvs::KvsEngine;
+
+    const KEYS: usize = 100_000;
+    const SCANNED: usize = 10_000;
+
+    // The same range scanned with the keys written in random order, then rewritten in
+    // key order.
+    pub fn suite_main(ct: &mut Criterion) {
+        let temp_dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            compaction_threshold: Some(usize::MAX),
+            ..Default::default()
+        };
+        let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+        let mut keys: Vec<_> = (0..KEYS).map(|i| format!("key{:06}", i)).collect();
+        keys.shuffle(&mut rand::thread_rng());
+        let value = "v".repeat(100);
+        for key in &keys {
+            store.set(key, &value).unwrap();
+        }
+        let start = format!("key{:06}", KEYS / 2);
+        let mut group = ct.benchmark_group("Scan a range of 10k keys");
+        group.sample_size(10);
+        group.bench_function("scattered", |b| {
+            b.iter(|| store.scan_after(Some(&start), SCANNED).unwrap())
+        });
+        store.defragment().unwrap();
+        group.bench_function("defragmented", |b| {
+            b.iter(|| store.scan_after(Some(&start), SCANNED).unwrap())
+        });
+        group.finish();
     }
 }
-criterion_group!(benches, engine::engine_test_suite, thread_pool::suite_main);
+criterion_group!(
+    benches,
+    engine::engine_test_suite,
+    thread_pool::suite_main,
+    compaction::suite_main,
+    large_reads::suite_main,
+    hot_key::suite_main,
+    open::suite_main,
+    defragment::suite_main
+);
 criterion_main!(benches);
@@ -0,0 +1,138 @@
+//! In a binary of its own, as the allocator counts the allocations of every benchmark.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{BatchSize, Criterion, Throughput};
+use tempfile::TempDir;
+
+use kvs::engine::{KvStore, KvStoreConfig};
+use kvs::KvsEngine;
+
+const KEYS: usize = 1000;
+
+/// Counts the allocations, to compare how many each way of setting takes.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Measures the allocations made rather than the time taken.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: usize) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &usize, v2: &usize) -> usize {
+        v1 + v2
+    }
+
+    fn zero(&self) -> usize {
+        0
+    }
+
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+struct AllocationFormatter;
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        if let Throughput::Elements(elements) = throughput {
+            for value in values {
+                *value /= *elements as f64;
+            }
+        }
+        "allocs/elem"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+// Pairs the caller already owns, set by reference then handed over.
+fn set_owned(ct: &mut Criterion<Allocations>) {
+    let temp_dir = TempDir::new().unwrap();
+    let config = KvStoreConfig {
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+    let pairs: Vec<_> = (0..KEYS)
+        .map(|i| (format!("key{:04}", i), "v".repeat(100)))
+        .collect();
+    let mut group = ct.benchmark_group("Allocations setting 1k owned pairs");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    group.bench_function("set", |b| {
+        b.iter_batched(
+            || pairs.clone(),
+            |pairs| {
+                for (key, value) in &pairs {
+                    store.set(key, value).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("set_owned", |b| {
+        b.iter_batched(
+            || pairs.clone(),
+            |pairs| {
+                for (key, value) in pairs {
+                    store.set_owned(key, value).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn main() {
+    // After the arguments, which turn the plots back on: the counts barely vary,
+    // which the plots fail to draw.
+    let mut criterion = Criterion::default()
+        .with_measurement(Allocations)
+        .configure_from_args()
+        .without_plots();
+    set_owned(&mut criterion);
+    criterion.final_summary();
+}
@@ -0,0 +1,288 @@
+//! Server on a tokio runtime, for more connections than threads.
+use std::fmt::Debug;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+use crate::metrics::op_name;
+use crate::protocol::{Framing, COMPRESSED_FRAME, LENGTH_PREFIX, LINES_PREFIX, MAX_MESSAGE_LEN};
+use crate::server::{
+    process_instruction, token_matches, AppliedRequests, Reservation, SCAN_PAGE_SIZE,
+};
+use crate::stream::resolve;
+use crate::{Instruction, KvsEngine, Metrics, Response, ScanFrame};
+
+/// Engine calls running at once unless configured.
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// Speaks the protocol of `KvServer`, serving each connection as a task rather than a thread.
+/// The engine calls run on the blocking pool of the runtime, at most `max_in_flight` at once,
+/// the connections beyond wait for their turn without reading further.
+/// Both framings, authentication, scans and idempotency keys are served. Compression is
+/// refused, clients then stay uncompressed, and so is replication.
+pub struct AsyncKvServer<T: KvsEngine> {
+    listener: std::net::TcpListener,
+    engine: T,
+    max_in_flight: usize,
+    auth_token: Option<String>,
+    metrics: Arc<Metrics>,
+}
+
+/// What a connection task needs from the server.
+#[derive(Clone)]
+struct ConnectionContext {
+    permits: Arc<Semaphore>,
+    auth_token: Option<Arc<str>>,
+    metrics: Arc<Metrics>,
+    /// Shared by the connections, retries usually come on a new one.
+    applied: Arc<Mutex<AppliedRequests>>,
+}
+
+impl<T: KvsEngine> AsyncKvServer<T> {
+    /// Bind `address`, connections are accepted once `run` is polled.
+    pub fn new(engine: T, address: impl ToSocketAddrs + Debug) -> Result<Self> {
+        let addrs = resolve(&address)?;
+        let listener = std::net::TcpListener::bind(&addrs[..])
+            .with_context(|| format!("Failed to bind {:?}.", addrs))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            engine,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            auth_token: None,
+            metrics: Default::default(),
+        })
+    }
+
+    /// Run at most `max_in_flight` engine calls at once.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Require clients to send an `Auth` instruction carrying `token` before anything else.
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Accept connections until the runtime shuts down, must run within a tokio runtime.
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::from_std(self.listener)?;
+        let context = ConnectionContext {
+            permits: Arc::new(Semaphore::new(self.max_in_flight)),
+            auth_token: self.auth_token.map(Arc::from),
+            metrics: self.metrics,
+            applied: Default::default(),
+        };
+        loop {
+            let (stream, client_addr) = listener.accept().await?;
+            info!("Accept connection from {:?}", client_addr);
+            let engine = self.engine.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                context.metrics.connection_opened();
+                if let Err(e) = serve_connection(engine, stream, &context).await {
+                    error!("Failed to serve {:?}: {:#}", client_addr, e);
+                }
+                context.metrics.connection_closed();
+                info!("Client: {:?} disconnected", client_addr);
+            });
+        }
+    }
+}
+
+async fn serve_connection<T: KvsEngine>(
+    engine: T,
+    stream: TcpStream,
+    context: &ConnectionContext,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let framing = match detect_framing(&mut reader).await? {
+        Some(framing) => framing,
+        None => return Ok(()),
+    };
+    let mut authenticated = context.auth_token.is_none();
+    // Requests are read and answers encoded here, they keep their capacity.
+    let mut request = Vec::new();
+    let mut reply = Vec::new();
+    while let Some(ins) = read_instruction(&mut reader, framing, &mut request).await? {
+        let (request_id, ins) = ins.untraced();
+        let trace = request_id.as_deref().unwrap_or("-");
+        debug!("[client->server] [{}] {:?}", trace, ins);
+        context.metrics.record_op(&ins);
+        let (resp, close) = match (&ins, &context.auth_token) {
+            (Instruction::Auth { token }, Some(expected)) => {
+                authenticated = token_matches(token, expected);
+                if authenticated {
+                    (Response::Ok("".to_owned()), false)
+                } else {
+                    warn!("Client sent a wrong auth token.");
+                    (Response::Error("Authentication failed.".to_owned()), true)
+                }
+            }
+            (_, Some(_)) if !authenticated => {
+                (Response::Error("Authentication required.".to_owned()), true)
+            }
+            (Instruction::Auth { .. }, None) => (Response::Ok("".to_owned()), false),
+            (Instruction::ScanAll, _) => {
+                if let Err(e) = stream_scan(engine.clone(), &mut writer, framing, context).await {
+                    context.metrics.record_error();
+                    return Err(e.context("Scan aborted."));
+                }
+                continue;
+            }
+            (Instruction::Metrics, _) => (Response::Ok(context.metrics.render()), false),
+            (
+                Instruction::Get { .. }
+                | Instruction::Set { .. }
+                | Instruction::Rm { .. }
+                | Instruction::ValueLen { .. }
+                | Instruction::MultiGet { .. }
+                | Instruction::RemoveMany { .. }
+                | Instruction::Keys { .. }
+                | Instruction::Transaction { .. },
+                _,
+            ) => (apply(engine.clone(), ins, context).await?, false),
+            _ => {
+                context.metrics.record_error();
+                let refused = format!("{} is not served here.", op_name(&ins));
+                (Response::Error(refused), false)
+            }
+        };
+        debug!("[server->client] [{}] {:?}", trace, resp);
+        framing.encode(&mut reply, &resp.traced(request_id))?;
+        writer.write_all(&reply).await?;
+        if close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Consume the prefix choosing the framing of the connection, see `Framing::detect`.
+async fn detect_framing(reader: &mut BufReader<OwnedReadHalf>) -> Result<Option<Framing>> {
+    let framing = match reader.fill_buf().await?.first().copied() {
+        None => return Ok(None),
+        Some(LINES_PREFIX) => Framing::Lines,
+        Some(LENGTH_PREFIX) => Framing::LengthPrefixed,
+        Some(_) => return Ok(Some(Framing::Lines)),
+    };
+    reader.consume(1);
+    Ok(Some(framing))
+}
+
+/// Read the next instruction into `buf`, `None` once the client closed the connection.
+/// Compression is never negotiated here, so a compressed message is refused.
+async fn read_instruction(
+    reader: &mut BufReader<OwnedReadHalf>,
+    framing: Framing,
+    buf: &mut Vec<u8>,
+) -> Result<Option<Instruction>> {
+    buf.clear();
+    match framing {
+        Framing::Lines => {
+            if reader.fill_buf().await?.first() == Some(&COMPRESSED_FRAME) {
+                bail!("Compressed message, compression is not served here.");
+            }
+            if reader.read_until(b'\n', buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+        Framing::LengthPrefixed => {
+            if reader.fill_buf().await?.is_empty() {
+                return Ok(None);
+            }
+            let len = reader.read_u32().await? as u64;
+            if len > MAX_MESSAGE_LEN {
+                bail!("Message of {} bytes is too long.", len);
+            }
+            (&mut *reader).take(len).read_to_end(buf).await?;
+            if buf.len() as u64 != len {
+                bail!("Message cut after {} of {} bytes.", buf.len(), len);
+            }
+        }
+    }
+    serde_json::from_slice(buf).map(Some).with_context(|| {
+        format!(
+            "Error when parsing from json. {}",
+            String::from_utf8_lossy(buf)
+        )
+    })
+}
+
+/// Run `ins` on the blocking pool once a permit is free. A set carrying an idempotency key
+/// is applied once, its answer recorded by the engine call itself.
+async fn apply<T: KvsEngine>(
+    mut engine: T,
+    ins: Instruction,
+    context: &ConnectionContext,
+) -> Result<Response> {
+    let reservation = match Reservation::take(&context.applied, &ins) {
+        Ok(reservation) => reservation,
+        Err(resp) => return Ok(resp),
+    };
+    let _permit = context.permits.clone().acquire_owned().await?;
+    let metrics = context.metrics.clone();
+    tokio::task::spawn_blocking(move || {
+        let resp = process_instruction(&mut engine, &ins, &metrics)?;
+        if ins.is_write() {
+            if let Err(e) = engine.flush() {
+                error!("Failed to flush the engine: {:?}", e);
+            }
+        }
+        reservation.record(&resp);
+        Ok(resp)
+    })
+    .await?
+}
+
+/// Send every pair a page at a time, each page read on the blocking pool and written at once.
+/// An engine failure is reported to the client, only write failures are returned.
+async fn stream_scan<T: KvsEngine>(
+    engine: T,
+    writer: &mut OwnedWriteHalf,
+    framing: Framing,
+    context: &ConnectionContext,
+) -> Result<()> {
+    let mut after: Option<String> = None;
+    loop {
+        let permit = context.permits.clone().acquire_owned().await?;
+        let scanned = {
+            let engine = engine.clone();
+            let after = after.clone();
+            tokio::task::spawn_blocking(move || engine.scan_after(after.as_deref(), SCAN_PAGE_SIZE))
+                .await?
+        };
+        drop(permit);
+        let mut frames = Vec::new();
+        let page = match scanned {
+            Ok(page) => page,
+            Err(e) => {
+                error!("Scan failed: {:?}", e);
+                framing.write(&mut frames, &ScanFrame::Error(e.to_string()))?;
+                writer.write_all(&frames).await?;
+                return Ok(());
+            }
+        };
+        let last_page = page.len() < SCAN_PAGE_SIZE;
+        for (key, value) in page {
+            framing.write(&mut frames, &ScanFrame::Pair(key.clone(), value))?;
+            after = Some(key);
+        }
+        if last_page {
+            framing.write(&mut frames, &ScanFrame::End)?;
+        }
+        writer.write_all(&frames).await?;
+        if last_page {
+            return Ok(());
+        }
+    }
+}
@@ -1,12 +1,73 @@
 use std::net::SocketAddrV4;
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
 use std::process::exit;
 
+use anyhow::Result;
+use serde_json::json;
 use structopt::*;
 
-use kvs::KvClient;
+use kvs::{KvClient, KvError, OutputFormat};
+
+#[derive(Debug, StructOpt)]
+struct ConnectOpts {
+    #[structopt(short = "a", long = "addr", default_value = "127.0.0.1:4000")]
+    address: SocketAddrV4,
+    #[structopt(long = "auth-token", help = "Token required by the server.")]
+    token: Option<String>,
+    #[cfg(feature = "tls")]
+    #[structopt(long = "tls", help = "Connect to the server over TLS.")]
+    tls: bool,
+    #[cfg(feature = "tls")]
+    #[structopt(
+        long = "tls-name",
+        default_value = "localhost",
+        help = "Server name to verify the certificate against."
+    )]
+    server_name: String,
+    #[cfg(feature = "tls")]
+    #[structopt(long = "tls-ca", help = "PEM file of the CA to trust.")]
+    ca: Option<PathBuf>,
+}
+
+impl ConnectOpts {
+    fn connect(&self) -> Result<KvClient, Failure> {
+        let mut client = self.connect_stream().map_err(|e| {
+            Failure::Connection(format!(
+                "Cannot reach the server at {}: {}",
+                self.address, e
+            ))
+        })?;
+        if let Some(token) = &self.token {
+            client.authenticate(token.as_str()).map_err(Failure::from)?;
+        }
+        Ok(client)
+    }
+
+    fn connect_stream(&self) -> Result<KvClient> {
+        #[cfg(feature = "tls")]
+        if self.tls {
+            return KvClient::connect_tls(self.address, &self.server_name, self.ca.as_deref());
+        }
+        KvClient::connect(self.address)
+    }
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-client", version = env ! ("CARGO_PKG_VERSION"))]
+struct Opt {
+    #[structopt(
+        long = "format",
+        default_value = "text",
+        global = true,
+        help = "Output format, text or json."
+    )]
+    format: OutputFormat,
+    #[structopt(subcommand)]
+    command: ArgParser,
+}
+
+#[derive(Debug, StructOpt)]
 #[allow(non_camel_case_types)]
 enum ArgParser {
     #[structopt(about = "Insert a key-value pair to storage.")]
@@ -15,46 +76,121 @@ enum ArgParser {
         key: String,
         #[structopt(about = "The value to be inserted.")]
         value: String,
-        #[structopt(short = "a", long = "addr", default_value = "127.0.0.1:4000")]
-        address: SocketAddrV4,
+        #[structopt(flatten)]
+        conn: ConnectOpts,
     },
     #[structopt(about = "Get a record by the provided key.")]
     get {
         #[structopt(about = "The key of the value to take.")]
         key: String,
-        #[structopt(short = "a", long = "addr", default_value = "127.0.0.1:4000")]
-        address: SocketAddrV4,
+        #[structopt(flatten)]
+        conn: ConnectOpts,
     },
     #[structopt(about = "Remove an existing record by the provided key.")]
     rm {
         #[structopt(about = "The key of the value to remove.")]
         key: String,
-        #[structopt(short = "a", long = "addr", default_value = "127.0.0.1:4000")]
-        address: SocketAddrV4,
+        #[structopt(flatten)]
+        conn: ConnectOpts,
     },
 }
 
-#[allow(unused)]
-fn main() {
-    let matches = ArgParser::from_args();
-    let reply = match matches {
-        ArgParser::set {
-            key,
-            value,
-            address,
-        } => KvClient::connect(address).and_then(|mut client| client.set(key, value)),
-        ArgParser::get { key, address } => {
-            KvClient::connect(address).and_then(|mut client| client.get(key))
-        }
-        ArgParser::rm { key, address } => {
-            KvClient::connect(address).and_then(|mut client| client.remove(key))
+enum Reply {
+    Done,
+    Value { key: String, value: Option<String> },
+}
+
+impl Reply {
+    fn print(self, format: OutputFormat) {
+        match (format, self) {
+            (OutputFormat::Text, Reply::Done) => println!(),
+            (OutputFormat::Text, Reply::Value { key, value }) => match value {
+                Some(value) => println!("{}", value),
+                None => println!("Key: {} not found", key),
+            },
+            (OutputFormat::Json, Reply::Done) => println!("{}", json!({ "ok": true })),
+            (OutputFormat::Json, Reply::Value { key, value }) => match value {
+                Some(value) => println!("{}", json!({ "key": key, "value": value })),
+                None => println!("{}", json!({ "key": key, "found": false })),
+            },
+        }
+    }
+}
+
+/// Why a command failed, each kind exits with its own code.
+enum Failure {
+    /// The key does not exist.
+    NotFound(String),
+    /// The server could not be reached or the connection broke.
+    Connection(String),
+    /// The server answered something unreadable.
+    Protocol(String),
+    /// The server refused the request.
+    Server(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::NotFound(_) => 1,
+            Failure::Connection(_) => 2,
+            Failure::Protocol(_) => 3,
+            Failure::Server(_) => 4,
         }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Failure::NotFound(msg)
+            | Failure::Connection(msg)
+            | Failure::Protocol(msg)
+            | Failure::Server(msg) => msg,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(err @ KvError::KeyNotFound(_)) = e.downcast_ref::<KvError>() {
+            Failure::NotFound(err.to_string())
+        } else if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+            Failure::Connection(format!("Connection to the server failed: {}", io_err))
+        } else if e.chain().any(|cause| cause.is::<serde_json::Error>()) {
+            Failure::Protocol(format!("Unexpected reply from the server: {:#}", e))
+        } else {
+            Failure::Server(format!("Server error: {}", e))
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let reply = match opt.command {
+        ArgParser::set { key, value, conn } => conn.connect().and_then(|mut client| {
+            client.set(key, value).map_err(Failure::from)?;
+            Ok(Reply::Done)
+        }),
+        ArgParser::get { key, conn } => conn.connect().and_then(|mut client| {
+            let value = client.get(key.clone()).map_err(Failure::from)?;
+            Ok(Reply::Value { key, value })
+        }),
+        ArgParser::rm { key, conn } => conn.connect().and_then(|mut client| {
+            client.remove(key).map_err(Failure::from)?;
+            Ok(Reply::Done)
+        }),
     };
-    match reply {
-        Ok(s) => println!("{}", s),
-        Err(e) => {
-            eprintln!("{}", e);
-            exit(-1)
+    match (opt.format, reply) {
+        (format, Ok(reply)) => reply.print(format),
+        (OutputFormat::Text, Err(failure)) => {
+            eprintln!("{}", failure.message());
+            exit(failure.exit_code())
+        }
+        (OutputFormat::Json, Err(failure)) => {
+            println!(
+                "{}",
+                json!({ "ok": false, "error": failure.message(), "code": failure.exit_code() })
+            );
+            exit(failure.exit_code())
         }
     }
 }
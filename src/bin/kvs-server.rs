@@ -1,90 +1,226 @@
-use std::fs::{File, OpenOptions};
-use std::io::Read;
-use std::io::Write;
-use std::net::{SocketAddrV4, ToSocketAddrs};
+use std::net::SocketAddrV4;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use anyhow::{Context, Result};
 use log::*;
+use serde::Deserialize;
 use simple_logger::SimpleLogger;
 use structopt::*;
 
-use kvs::engine::{KvStore, SledAdapter};
+use kvs::engine::{detect_engine, KvStore, KvStoreConfig, SledAdapter, ENGINE_MARK_FILE};
 use kvs::thread_pool::{RayonThreadPool, ThreadPool};
-use kvs::{EngineType, KvServer, KvsEngine};
+use kvs::{EngineType, FlushPolicy, KvServer, KvsEngine, Metrics};
 
-const ENGINE_MARK_FILE: &'static str = ".engine_mark";
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+const DEFAULT_THREADS: u32 = 4;
 
-/// KVServer configuration.
+/// KVServer configuration, flags left out are taken from `--config` then the defaults.
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-server", version = env ! ("CARGO_PKG_VERSION"))]
 struct ServerConfig {
-    #[structopt(short = "a", long = "addr", default_value = "127.0.0.1:4000")]
-    address: SocketAddrV4,
-    #[structopt(short = "t", long = "engine", default_value = "kvs")]
-    engine_type: EngineType,
+    #[structopt(long = "config", help = "TOML file providing defaults for the flags.")]
+    config_file: Option<PathBuf>,
+    #[structopt(short = "a", long = "addr", help = "[default: 127.0.0.1:4000]")]
+    address: Option<SocketAddrV4>,
+    #[structopt(
+        short = "t",
+        long = "engine",
+        help = "[default: the engine of the existing data, else kvs]"
+    )]
+    engine_type: Option<EngineType>,
+    #[structopt(long = "threads", help = "Worker threads. [default: 4]")]
+    threads: Option<u32>,
+    #[structopt(long = "log-level", help = "[default: debug]")]
+    log_level: Option<LevelFilter>,
+    #[structopt(
+        long = "flush-every",
+        help = "Flush the engine every N writes instead of after each one."
+    )]
+    flush_every: Option<usize>,
+    #[structopt(
+        long = "data-dir",
+        help = "Directory holding the data, the current directory by default."
+    )]
+    data_dir: Option<PathBuf>,
+    #[structopt(
+        long = "auth-token",
+        help = "Shared token clients must authenticate with."
+    )]
+    auth_token: Option<String>,
+    #[structopt(
+        long = "readonly",
+        help = "Open the existing data read-only and reject every write."
+    )]
+    read_only: bool,
+    #[structopt(
+        long = "sample-reads",
+        help = "Count one kvs read in N, listed by `kvs hotkeys` once the server stops."
+    )]
+    sample_reads: Option<u32>,
+    #[cfg(feature = "tls")]
+    #[structopt(
+        long = "tls-cert",
+        requires = "tls-key",
+        help = "PEM certificate chain."
+    )]
+    tls_cert: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    #[structopt(long = "tls-key", requires = "tls-cert", help = "PEM private key.")]
+    tls_key: Option<PathBuf>,
+}
+
+/// Settings read from `--config`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    addr: Option<SocketAddrV4>,
+    engine: Option<String>,
+    threads: Option<u32>,
+    log_level: Option<String>,
+    data_dir: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// Fill the flags left out from the config file, if any.
+    fn merge_file(mut self) -> Result<Self> {
+        let path = match &self.config_file {
+            Some(path) => path,
+            None => return Ok(self),
+        };
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        let file: FileConfig =
+            toml::from_str(&content).with_context(|| format!("Invalid config file {:?}", path))?;
+        self.address = self.address.or(file.addr);
+        if self.engine_type.is_none() {
+            self.engine_type = file
+                .engine
+                .as_deref()
+                .map(EngineType::from_str)
+                .transpose()?;
+        }
+        self.threads = self.threads.or(file.threads);
+        if self.log_level.is_none() {
+            self.log_level = file
+                .log_level
+                .as_deref()
+                .map(LevelFilter::from_str)
+                .transpose()
+                .context("Invalid log level")?;
+        }
+        self.data_dir = self.data_dir.or(file.data_dir);
+        Ok(self)
+    }
+
+    fn address(&self) -> SocketAddrV4 {
+        self.address
+            .unwrap_or_else(|| DEFAULT_ADDRESS.parse().unwrap())
+    }
+
+    fn engine_type(&self) -> EngineType {
+        self.engine_type.unwrap_or(EngineType::Kvs)
+    }
 }
 
 fn main() {
+    let mut config = ServerConfig::from_args()
+        .merge_file()
+        .expect("Failed to load the configuration.");
     SimpleLogger::new()
-        .with_level(LevelFilter::Debug)
+        .with_level(config.log_level.unwrap_or(LevelFilter::Debug))
         .init()
         .unwrap();
-    let current_dir = std::env::current_dir().unwrap();
-    let config = ServerConfig::from_args();
-    // check directory.
-    let (prev_engine, mut mark_fp) = read_from_mark_file(&current_dir);
-    match prev_engine {
-        Some(prev) => {
+    let data_dir = match &config.data_dir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir().unwrap(),
+    };
+    if !config.read_only {
+        std::fs::create_dir_all(&data_dir).expect("Failed to create the data directory.");
+    }
+    let detected = detect_engine(&data_dir).expect("Failed to inspect the data directory.");
+    match (config.engine_type, detected) {
+        (Some(chosen), Some(prev)) if chosen != prev => panic!(
+            "Mismatched engine type!, previous engine: {}, new engine: {}",
+            prev, chosen
+        ),
+        (_, Some(prev)) => {
             info!("Retrieving last work. engine: {}", prev);
-            if prev != config.engine_type {
-                panic!(
-                    "Mismatched engine type!, previous engine: {}, new engine: {}",
-                    prev, config.engine_type
-                )
-            }
-        }
-        None => {
-            write!(mark_fp, "{}", String::from(config.engine_type)).unwrap();
+            config.engine_type = Some(prev);
         }
+        (_, None) if config.read_only => panic!("No data to serve read-only in {:?}", data_dir),
+        (_, None) => (),
+    }
+    if !config.read_only {
+        std::fs::write(
+            data_dir.join(ENGINE_MARK_FILE),
+            String::from(config.engine_type()),
+        )
+        .expect("Failed to write the engine mark.");
     }
     info!(
-        "Listened at {}, powered by {}, version: {}",
-        config.address,
-        config.engine_type,
-        env!("CARGO_PKG_VERSION")
+        "Listened at {}, powered by {}, version: {}, data directory: {:?}",
+        config.address(),
+        config.engine_type(),
+        env!("CARGO_PKG_VERSION"),
+        data_dir
     );
-    match &config.engine_type {
-        EngineType::Kvs => run_with(
-            KvStore::open(current_dir.as_path()).expect("Failed to create a server."),
-            config.address,
-        ),
+    let metrics = Arc::new(Metrics::default());
+    match config.engine_type() {
+        EngineType::Kvs => {
+            let store_config = KvStoreConfig {
+                read_only: config.read_only,
+                sample_reads: config.sample_reads,
+                ..Default::default()
+            };
+            let store = KvStore::open_with_config(data_dir.as_path(), store_config)
+                .expect("Failed to create a server.");
+            let compactions = metrics.clone();
+            store
+                .on_compaction(Box::new(move |stats| {
+                    compactions.record_compaction();
+                    info!("Compaction done: {:?}", stats)
+                }))
+                .unwrap();
+            run_with(store, &config, metrics)
+        }
         EngineType::Sled => run_with(
-            SledAdapter::open(current_dir.as_path()).expect("Failed to create a sled engine."),
-            config.address,
+            SledAdapter::open(data_dir.as_path()).expect("Failed to create a sled engine."),
+            &config,
+            metrics,
         ),
         _ => todo!(),
     }
 }
 
-fn run_with<T: KvsEngine>(engine: T, address: impl ToSocketAddrs) {
-    let server = KvServer::new(engine, RayonThreadPool::new(4).unwrap(), address).unwrap();
-    server.run()
-}
-
-fn read_from_mark_file(dir: &PathBuf) -> (Option<EngineType>, File) {
-    let mut lock_fp = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .open(dir.join(ENGINE_MARK_FILE))
-        .unwrap();
-    let prev_engine = {
-        let mut buf = String::new();
-        lock_fp
-            .read_to_string(&mut buf)
-            .expect("Failed to read from mark file");
-        EngineType::from_str(&buf).ok()
+fn run_with<T: KvsEngine>(engine: T, config: &ServerConfig, metrics: Arc<Metrics>) {
+    let threads = config.threads.unwrap_or(DEFAULT_THREADS);
+    let mut server = KvServer::new(
+        engine,
+        RayonThreadPool::new(threads).unwrap(),
+        config.address(),
+    )
+    .unwrap()
+    .with_metrics(metrics)
+    .with_read_only(config.read_only);
+    if let Some(n) = config.flush_every {
+        server = server.with_flush_policy(FlushPolicy::EveryN(n));
+    }
+    if let Some(token) = &config.auth_token {
+        server = server.with_auth_token(token.as_str());
+    }
+    #[cfg(feature = "tls")]
+    let server = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => server.with_tls(cert, key).expect("Failed to set up TLS."),
+        _ => server,
     };
-    (prev_engine, lock_fp)
+    let handle = server.handle().unwrap();
+    ctrlc::set_handler(move || {
+        info!("Termination signal received, shutting down gracefully.");
+        handle.shutdown();
+    })
+    .expect("Failed to install the signal handler.");
+    server.run().expect("Server aborted.");
+    info!("Server stopped.");
 }
@@ -1,13 +1,33 @@
 use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::json;
 use structopt::*;
 
-use kvs::engine::KvStore;
-use kvs::KvsEngine;
+use kvs::engine::{IntegrityIssue, KvStore, SegmentInfo};
+use kvs::{KvsEngine, OutputFormat};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = env ! ("CARGO_PKG_NAME"), version = env ! ("CARGO_PKG_VERSION"))]
+struct Opt {
+    #[structopt(
+        long = "format",
+        default_value = "text",
+        global = true,
+        help = "Output format, text or json."
+    )]
+    format: OutputFormat,
+    #[structopt(subcommand)]
+    command: ArgParser,
+}
+
+#[derive(Debug, StructOpt)]
 #[allow(non_camel_case_types)]
 enum ArgParser {
     #[structopt(about = "Insert a key-value pair to storage.")]
@@ -27,24 +47,194 @@ enum ArgParser {
         #[structopt(about = "The key of the value to remove.")]
         key: String,
     },
+    #[structopt(about = "Check the store on disk without modifying it.")]
+    verify,
+    #[structopt(about = "List the log files with their live and dead records.")]
+    segments,
+    #[structopt(about = "List the most read keys, as counted by a store sampling reads.")]
+    hotkeys {
+        #[structopt(short = "n", default_value = "10", help = "How many keys to list.")]
+        count: usize,
+    },
+    #[structopt(about = "Insert the pairs of a JSON-lines file of {\"key\":..,\"value\":..}.")]
+    load {
+        #[structopt(about = "The file to load.", parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+/// Pairs written at once by `load`.
+const LOAD_BATCH_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Insert the pairs of the JSON-lines `file` in batches, returning how many there were.
+fn load(store: &KvStore, file: &Path) -> Result<usize> {
+    let reader = File::open(file)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open {:?}", file))?;
+    let mut batch = Vec::with_capacity(LOAD_BATCH_SIZE);
+    let mut count = 0;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let pair: Pair = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid pair on line {} of {:?}", i + 1, file))?;
+        batch.push((pair.key, pair.value));
+        if batch.len() == LOAD_BATCH_SIZE {
+            count += batch.len();
+            store.set_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    count += batch.len();
+    store.set_batch(batch)?;
+    Ok(count)
+}
+
+enum Reply {
+    Done,
+    Value { key: String, value: Option<String> },
+    Issues(Vec<IntegrityIssue>),
+    Segments(Vec<SegmentInfo>),
+    HotKeys(Vec<(String, u64)>),
+    Loaded { count: usize, elapsed: Duration },
+}
+
+impl Reply {
+    fn print(self, format: OutputFormat) {
+        match (format, self) {
+            (OutputFormat::Text, Reply::Done) => (),
+            (OutputFormat::Text, Reply::Value { value: Some(v), .. }) => println!("{}", v),
+            (OutputFormat::Text, Reply::Value { key, value: None }) => {
+                println!("Key: {} not found", key)
+            }
+            (OutputFormat::Json, Reply::Done) => println!("{}", json!({ "ok": true })),
+            (
+                OutputFormat::Json,
+                Reply::Value {
+                    key,
+                    value: Some(v),
+                },
+            ) => {
+                println!("{}", json!({ "key": key, "value": v }))
+            }
+            (OutputFormat::Json, Reply::Value { key, value: None }) => {
+                println!("{}", json!({ "key": key, "found": false }))
+            }
+            (OutputFormat::Text, Reply::Issues(issues)) => {
+                for issue in issues.iter() {
+                    println!("{}", issue)
+                }
+                println!("{} problem(s) found.", issues.len())
+            }
+            (OutputFormat::Json, Reply::Issues(issues)) => {
+                let issues: Vec<_> = issues.iter().map(ToString::to_string).collect();
+                println!("{}", json!({ "ok": issues.is_empty(), "problems": issues }))
+            }
+            (OutputFormat::Text, Reply::Segments(segments)) => {
+                println!("{:>8} {:>12} {:>8} {:>8}", "file", "bytes", "live", "dead");
+                for s in segments {
+                    println!(
+                        "{:>8} {:>12} {:>8} {:>8}",
+                        s.file_id, s.size_bytes, s.live_records, s.dead_records
+                    )
+                }
+            }
+            (OutputFormat::Json, Reply::Segments(segments)) => {
+                let segments: Vec<_> = segments
+                    .iter()
+                    .map(|s| {
+                        json!({
+                            "file_id": s.file_id,
+                            "size_bytes": s.size_bytes,
+                            "live_records": s.live_records,
+                            "dead_records": s.dead_records,
+                        })
+                    })
+                    .collect();
+                println!("{}", json!({ "segments": segments }))
+            }
+            (OutputFormat::Text, Reply::HotKeys(keys)) => {
+                for (key, reads) in keys {
+                    println!("{:>10} {}", reads, key)
+                }
+            }
+            (OutputFormat::Json, Reply::HotKeys(keys)) => {
+                let keys: Vec<_> = keys
+                    .iter()
+                    .map(|(key, reads)| json!({ "key": key, "reads": reads }))
+                    .collect();
+                println!("{}", json!({ "hot_keys": keys }))
+            }
+            (OutputFormat::Text, Reply::Loaded { count, elapsed }) => println!(
+                "Loaded {} pairs in {:.2?}, {:.0} pairs/s.",
+                count,
+                elapsed,
+                count as f64 / elapsed.as_secs_f64()
+            ),
+            (OutputFormat::Json, Reply::Loaded { count, elapsed }) => println!(
+                "{}",
+                json!({ "ok": true, "loaded": count, "seconds": elapsed.as_secs_f64() })
+            ),
+        }
+    }
 }
 
 #[allow(unused)]
 fn main() -> Result<()> {
-    let matches = ArgParser::from_args();
-    match matches {
-        ArgParser::set { key, value } => {
-            KvStore::open(env::current_dir().unwrap())?.set(&key, &value)
+    let opt = Opt::from_args();
+    let store = || KvStore::open(env::current_dir().unwrap());
+    let result = match opt.command {
+        ArgParser::set { key, value } => store()
+            .and_then(|store| store.set(&key, &value))
+            .map(|_| Reply::Done),
+        ArgParser::get { key } => store()
+            .and_then(|store| store.get(&key))
+            .map(|value| Reply::Value { key, value }),
+        ArgParser::rm { key } => store()
+            .and_then(|store| store.remove(&key))
+            .map(|_| Reply::Done),
+        ArgParser::verify => KvStore::verify(env::current_dir()?).map(Reply::Issues),
+        ArgParser::segments => store()
+            .and_then(|store| store.segments())
+            .map(Reply::Segments),
+        ArgParser::hotkeys { count } => store()
+            .and_then(|store| store.hot_keys(count))
+            .map(Reply::HotKeys),
+        ArgParser::load { file } => {
+            let started = Instant::now();
+            store()
+                .and_then(|store| {
+                    let count = load(&store, &file)?;
+                    store.close()?;
+                    Ok(count)
+                })
+                .map(|count| Reply::Loaded {
+                    count,
+                    elapsed: started.elapsed(),
+                })
+        }
+    };
+    match (opt.format, result) {
+        (format, Ok(Reply::Issues(issues))) => {
+            let failed = !issues.is_empty();
+            Reply::Issues(issues).print(format);
+            if failed {
+                exit(1)
+            }
         }
-        ArgParser::get { key } => {
-            let logged = key.clone();
-            let value = KvStore::open(env::current_dir().unwrap())?.get(&key)?;
-            match &value {
-                Some(val) => println!("{}", val),
-                None => println!("Key: {} not found", logged),
-            };
-            Ok(())
+        (format, Ok(reply)) => reply.print(format),
+        (OutputFormat::Text, Err(e)) => return Err(e),
+        (OutputFormat::Json, Err(e)) => {
+            println!("{}", json!({ "ok": false, "error": e.to_string() }));
+            exit(1)
         }
-        ArgParser::rm { key } => KvStore::open(env::current_dir().unwrap())?.remove(&key),
     }
+    Ok(())
 }
@@ -1,33 +1,183 @@
-use std::io::{BufRead, BufReader, LineWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::fmt::Debug;
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::net::ToSocketAddrs;
+#[cfg(feature = "tls")]
+use std::path::Path;
 
 use anyhow::{bail, Context, Result};
+use log::*;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
 
-use crate::{Instruction, Response};
+use crate::engine::WriteOp;
+use crate::protocol::{write_message_compressed, Framing};
+use crate::stream::{self, Stream};
+use crate::{Instruction, KvError, Response, ScanFrame};
+
+/// Opens a new connection to the server the client was created for.
+type Connector = Box<dyn Fn() -> Result<Box<dyn Stream>> + Send>;
 
 pub struct CommandClient {
-    stream: TcpStream,
+    reader: BufReader<Box<dyn Stream>>,
+    connector: Connector,
+    /// Sent again after reconnecting, once the server accepted it.
+    auth_token: Option<String>,
+    /// Smallest request gzipped, once the server agreed to it.
+    compress_above: Option<usize>,
+    framing: Framing,
+    /// Whether requests are sent as `Instruction::Traced`.
+    trace_requests: bool,
+    last_request_id: Option<String>,
 }
 
 impl CommandClient {
-    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        Ok(Self { stream })
+    pub fn connect(addr: impl ToSocketAddrs + Debug) -> Result<Self> {
+        let addrs = stream::resolve(&addr)?;
+        Self::with_connector(Box::new(move || {
+            Ok(Box::new(stream::connect(&&addrs[..])?) as Box<dyn Stream>)
+        }))
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        addr: impl ToSocketAddrs + Debug,
+        server_name: &str,
+        ca: Option<&Path>,
+    ) -> Result<Self> {
+        use std::convert::TryFrom;
+
+        let config = crate::tls::client_config(ca)?;
+        let name = rustls::ServerName::try_from(server_name)
+            .map_err(|_| anyhow::anyhow!("Invalid server name: {}", server_name))?;
+        let addrs = stream::resolve(&addr)?;
+        Self::with_connector(Box::new(move || {
+            let connection = rustls::ClientConnection::new(config.clone(), name.clone())?;
+            let stream = stream::connect(&&addrs[..])?;
+            Ok(Box::new(rustls::StreamOwned::new(connection, stream)) as Box<dyn Stream>)
+        }))
+    }
+
+    fn with_connector(connector: Connector) -> Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(connector()?),
+            connector,
+            auth_token: None,
+            compress_above: None,
+            framing: Framing::Lines,
+            trace_requests: false,
+            last_request_id: None,
+        })
+    }
+
+    /// Speak `framing` from now on, must be called before anything was sent.
+    pub fn choose_framing(&mut self, framing: Framing) -> Result<()> {
+        self.reader.get_mut().write_all(&[framing.prefix()])?;
+        self.framing = framing;
+        Ok(())
+    }
+
+    /// Ask the server to gzip the messages of at least `min_size` bytes,
+    /// staying uncompressed if it refuses.
+    pub fn negotiate_compression(&mut self, min_size: usize) -> Result<()> {
+        self.send(&Instruction::Compress { min_size })?;
+        match self.read_frame()? {
+            Response::Ok(_) => self.compress_above = Some(min_size),
+            refused => info!("Server refused compression: {:?}", refused),
+        }
+        Ok(())
     }
 
     pub(crate) fn send_instruction(&mut self, ins: Instruction) -> Result<String> {
-        let mut buf_reader = BufReader::new(&self.stream);
-        let mut line_writer = LineWriter::new(&self.stream);
-        let serialized = serde_json::to_string(&ins)?;
-        writeln!(line_writer, "{}", serialized)?;
-        let mut buf = String::new();
-        buf_reader.read_line(&mut buf)?;
-        let resp: Response = serde_json::from_str(buf.trim())
-            .with_context(|| format!("Error when parsing from json. {}", buf))?;
-        match resp {
+        match self.request(ins)? {
             Response::Ok(s) => Ok(s),
             Response::Error(s) => bail!(s),
+            Response::NotFound(key) => Err(KvError::KeyNotFound(key).into()),
+            Response::Values(_) | Response::Removed(_) | Response::Keys { .. } => {
+                bail!("Unexpected batch answer to a single key.")
+            }
+            Response::Traced { .. } => bail!("Unexpected traced answer."),
+        }
+    }
+
+    /// Send `ins` and read its answer. If the server closed the connection, e.g. restarting,
+    /// reconnects once and sends `ins` again unless that could apply it twice.
+    pub(crate) fn request(&mut self, ins: Instruction) -> Result<Response> {
+        let e = match self.request_once(&ins) {
+            Err(e) if is_disconnected(&e) => e,
+            answer => return answer,
+        };
+        warn!("Connection to the server lost, reconnecting: {:#}", e);
+        self.reconnect()
+            .with_context(|| format!("Failed to reconnect after losing the connection: {:#}", e))?;
+        if !ins.is_retryable() {
+            bail!(
+                "Connection lost before the answer, the request may have been applied: {:#}",
+                e
+            );
+        }
+        self.request_once(&ins)
+    }
+
+    /// Send `ins` and read its answer, tagged with a new request id if tracing.
+    fn request_once(&mut self, ins: &Instruction) -> Result<Response> {
+        if !self.trace_requests {
+            self.send(ins)?;
+            return self.read_frame();
+        }
+        let request_id = Uuid::new_v4().to_string();
+        debug!("Send request {}: {:?}", request_id, ins);
+        self.send(&Instruction::Traced {
+            request_id: request_id.clone(),
+            instruction: Box::new(ins.clone()),
+        })?;
+        self.last_request_id = Some(request_id.clone());
+        match self.read_frame()? {
+            Response::Traced {
+                request_id: echoed,
+                response,
+            } if echoed == request_id => Ok(*response),
+            other => bail!(
+                "Answer to request {} without its id: {:?}",
+                request_id,
+                other
+            ),
+        }
+    }
+
+    /// Open a new connection, set up like the lost one: framing, token and compression.
+    fn reconnect(&mut self) -> Result<()> {
+        self.reader = BufReader::new((self.connector)()?);
+        if self.framing != Framing::Lines {
+            self.reader.get_mut().write_all(&[self.framing.prefix()])?;
+        }
+        if let Some(token) = self.auth_token.clone() {
+            match self.request_once(&Instruction::Auth { token })? {
+                Response::Ok(_) => (),
+                other => bail!("Failed to authenticate again: {:?}", other),
+            }
+        }
+        if let Some(min_size) = self.compress_above.take() {
+            self.negotiate_compression(min_size)?;
+        }
+        Ok(())
+    }
+
+    /// Send `ins` without waiting for the answer.
+    pub(crate) fn send(&mut self, ins: &Instruction) -> Result<()> {
+        let writer = self.reader.get_mut();
+        match self.compress_above {
+            Some(min_size) => write_message_compressed(writer, ins, min_size)?,
+            None => self.framing.write(writer, ins)?,
         }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read the next message sent by the server.
+    pub(crate) fn read_frame<F: DeserializeOwned>(&mut self) -> Result<F> {
+        self.framing.read(&mut self.reader)?.ok_or_else(|| {
+            io::Error::new(ErrorKind::UnexpectedEof, "Connection closed by the server.").into()
+        })
     }
 }
 
@@ -38,23 +188,211 @@ pub struct KvClient {
 
 impl KvClient {
     /// connect to KvServer listening on `addr`.
-    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+    pub fn connect(addr: impl ToSocketAddrs + Debug) -> Result<Self> {
         Ok(KvClient {
             client: CommandClient::connect(addr)?,
         })
     }
 
-    /// Get the value by provided key.
-    pub fn get(&mut self, key: String) -> Result<String> {
-        self.client.send_instruction(Instruction::Get { key })
+    /// connect to KvServer listening on `addr` over TLS.
+    /// The certificate is verified against `ca` if provided, the webpki roots otherwise.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        addr: impl ToSocketAddrs + Debug,
+        server_name: &str,
+        ca: Option<&Path>,
+    ) -> Result<Self> {
+        Ok(KvClient {
+            client: CommandClient::connect_tls(addr, server_name, ca)?,
+        })
+    }
+
+    /// connect to KvServer listening on `addr`, gzipping the messages of at least `min_size`
+    /// bytes both ways. Falls back to plain messages if the server doesn't support it.
+    pub fn connect_with_compression(
+        addr: impl ToSocketAddrs + Debug,
+        min_size: usize,
+    ) -> Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.client.negotiate_compression(min_size)?;
+        Ok(client)
+    }
+
+    /// connect to KvServer listening on `addr`, leading the connection with the prefix of
+    /// `framing`. Servers before length-prefixed framing only understand `Framing::Lines`.
+    pub fn connect_with_framing(
+        addr: impl ToSocketAddrs + Debug,
+        framing: Framing,
+    ) -> Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.client.choose_framing(framing)?;
+        Ok(client)
+    }
+
+    /// connect to KvServer listening on `addr`, authenticating with `token`.
+    pub fn connect_with_token(
+        addr: impl ToSocketAddrs + Debug,
+        token: impl Into<String>,
+    ) -> Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.authenticate(token)?;
+        Ok(client)
+    }
+
+    /// Tag each request with a new UUID from now on, which the server logs it with and
+    /// echoes back. Servers before request tracing don't understand them.
+    pub fn trace_requests(&mut self, enabled: bool) {
+        self.client.trace_requests = enabled;
     }
+
+    /// Id of the last request sent while tracing, to find it in the server logs.
+    pub fn last_request_id(&self) -> Option<&str> {
+        self.client.last_request_id.as_deref()
+    }
+
+    /// Authenticate the connection, must precede other requests if the server requires a token.
+    pub fn authenticate(&mut self, token: impl Into<String>) -> Result<()> {
+        let token = token.into();
+        self.client.send_instruction(Instruction::Auth {
+            token: token.clone(),
+        })?;
+        self.client.auth_token = Some(token);
+        Ok(())
+    }
+
+    /// Get the value by provided key, `None` if it does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        found(self.client.send_instruction(Instruction::Get { key }))
+    }
+
+    /// Values of `keys` in the same order, `None` for the missing ones, in one round trip.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.client.request(Instruction::MultiGet { keys })? {
+            Response::Values(values) => Ok(values),
+            Response::Error(s) => bail!(s),
+            other => bail!("Unexpected answer to a multi-get: {:?}", other),
+        }
+    }
+
+    /// Up to `limit` keys past `after` and the cursor of the next page, `None` at the end.
+    /// See `KvsEngine::keys_paginated`.
+    pub fn keys_paginated(
+        &mut self,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        match self.client.request(Instruction::Keys { after, limit })? {
+            Response::Keys { keys, next } => Ok((keys, next)),
+            Response::Error(s) => bail!(s),
+            other => bail!("Unexpected answer to a page of keys: {:?}", other),
+        }
+    }
+
+    /// Byte length of the value of `key`, `None` if it does not exist.
+    /// The value itself is not sent.
+    pub fn value_len(&mut self, key: String) -> Result<Option<usize>> {
+        found(self.client.send_instruction(Instruction::ValueLen { key }))?
+            .map(|len| len.parse().context("Invalid value length."))
+            .transpose()
+    }
+
     /// Insert a key-value pair.
     pub fn set(&mut self, key: String, value: String) -> Result<String> {
-        self.client
-            .send_instruction(Instruction::Set { key, value })
+        self.client.send_instruction(Instruction::Set {
+            key,
+            value,
+            idempotency_key: None,
+        })
+    }
+
+    /// Insert a key-value pair, safe to retry: the server answers a request carrying an
+    /// `idempotency_key` it recently applied without applying it again.
+    pub fn set_idempotent(
+        &mut self,
+        key: String,
+        value: String,
+        idempotency_key: String,
+    ) -> Result<String> {
+        self.client.send_instruction(Instruction::Set {
+            key,
+            value,
+            idempotency_key: Some(idempotency_key),
+        })
     }
     /// Remove an existing key-value pair or report error.
+    /// A missing key is reported as `KvError::KeyNotFound`.
     pub fn remove(&mut self, key: String) -> Result<String> {
         self.client.send_instruction(Instruction::Rm { key })
     }
+
+    /// Remove every key found in one round trip, telling for each whether it was.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        match self.client.request(Instruction::RemoveMany { keys })? {
+            Response::Removed(removed) => Ok(removed),
+            Response::Error(s) => bail!(s),
+            other => bail!("Unexpected answer to a batch removal: {:?}", other),
+        }
+    }
+
+    /// Apply every write of `ops` on the server, or none if one of them fails.
+    pub fn transaction(&mut self, ops: Vec<WriteOp>) -> Result<()> {
+        let ops = ops
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::Set { key, value } => Instruction::Set {
+                    key,
+                    value,
+                    idempotency_key: None,
+                },
+                WriteOp::Remove { key } => Instruction::Rm { key },
+            })
+            .collect();
+        self.client
+            .send_instruction(Instruction::Transaction { ops })
+            .map(|_| ())
+    }
+
+    /// The server counters in the Prometheus text format.
+    pub fn metrics(&mut self) -> Result<String> {
+        self.client.send_instruction(Instruction::Metrics)
+    }
+
+    /// Every key-value pair of the server, in key order.
+    /// The server streams them, it never holds the whole store in memory.
+    pub fn scan_all(&mut self) -> Result<Vec<(String, String)>> {
+        self.client.send(&Instruction::ScanAll)?;
+        let mut entries = Vec::new();
+        loop {
+            match self.client.read_frame()? {
+                ScanFrame::Pair(key, value) => entries.push((key, value)),
+                ScanFrame::End => return Ok(entries),
+                ScanFrame::Error(s) => bail!(s),
+            }
+        }
+    }
+}
+
+/// Whether `e` comes from the server closing the connection, rather than answering.
+fn is_disconnected(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::UnexpectedEof
+            )
+        })
+}
+
+/// `None` for a missing key, which the server reports as `KvError::KeyNotFound`.
+fn found<T>(reply: Result<T>) -> Result<Option<T>> {
+    match reply {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyNotFound(_))) => Ok(None),
+        Err(e) => Err(e),
+    }
 }
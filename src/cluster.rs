@@ -0,0 +1,116 @@
+//! Client spreading keys over independent servers.
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use anyhow::{bail, Result};
+
+use crate::engine::fnv1a;
+use crate::KvClient;
+
+/// Points of each server on the hash ring, the more the evener the spread.
+const VIRTUAL_NODES: usize = 64;
+
+/// Routes each key to one of several `KvServer`s by consistent hashing,
+/// so adding a server only moves the keys it takes over.
+/// Connections are opened on first use and kept.
+pub struct ClusterKvClient {
+    nodes: Vec<Node>,
+    /// Hash of each virtual node to the index of its server.
+    ring: BTreeMap<u64, usize>,
+}
+
+struct Node {
+    addr: SocketAddr,
+    client: Option<KvClient>,
+}
+
+impl ClusterKvClient {
+    /// Route over the servers at `addrs`, every client must be given the same ones.
+    pub fn new(addrs: Vec<SocketAddr>) -> Result<Self> {
+        if addrs.is_empty() {
+            bail!("At least one server address is required.");
+        }
+        let mut ring = BTreeMap::new();
+        for (index, addr) in addrs.iter().enumerate() {
+            for vnode in 0..VIRTUAL_NODES {
+                ring.insert(ring_hash(format!("{}#{}", addr, vnode).as_bytes()), index);
+            }
+        }
+        let nodes = addrs
+            .into_iter()
+            .map(|addr| Node { addr, client: None })
+            .collect();
+        Ok(Self { nodes, ring })
+    }
+
+    /// Address of the server holding `key`.
+    pub fn node_of(&self, key: &str) -> SocketAddr {
+        self.nodes[self.index_of(key)].addr
+    }
+
+    /// Get the value by provided key, `None` if it does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let index = self.index_of(&key);
+        self.call(index, |client| client.get(key))
+    }
+
+    /// Insert a key-value pair.
+    pub fn set(&mut self, key: String, value: String) -> Result<String> {
+        let index = self.index_of(&key);
+        self.call(index, |client| client.set(key, value))
+    }
+
+    /// Remove an existing key-value pair or report error.
+    pub fn remove(&mut self, key: String) -> Result<String> {
+        let index = self.index_of(&key);
+        self.call(index, |client| client.remove(key))
+    }
+
+    /// Every key-value pair of every server, in key order.
+    pub fn scan_all(&mut self) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        for index in 0..self.nodes.len() {
+            entries.extend(self.call(index, KvClient::scan_all)?);
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    /// First virtual node at or after the hash of `key`, wrapping around the ring.
+    fn index_of(&self, key: &str) -> usize {
+        let hash = ring_hash(key.as_bytes());
+        let (_, &index) = self
+            .ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .expect("the ring has a node per server");
+        index
+    }
+
+    /// Run `f` on the connection to the server `index`, dropped if it fails,
+    /// so the next call reconnects.
+    fn call<T>(&mut self, index: usize, f: impl FnOnce(&mut KvClient) -> Result<T>) -> Result<T> {
+        let node = &mut self.nodes[index];
+        let client = match &mut node.client {
+            Some(client) => client,
+            None => node.client.insert(KvClient::connect(node.addr)?),
+        };
+        let result = f(client);
+        if matches!(&result, Err(e) if e.downcast_ref::<std::io::Error>().is_some()) {
+            node.client = None;
+        }
+        result
+    }
+}
+
+/// FNV-1a spread over the whole ring, similar keys only differ in its low bits.
+fn ring_hash(bytes: &[u8]) -> u64 {
+    // Finalizer of MurmurHash3.
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,56 @@
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use crate::EngineType;
+
+/// File recording which engine a data directory was created with, written by `kvs-server`.
+pub const ENGINE_MARK_FILE: &str = ".engine_mark";
+
+/// Files sled keeps at the root of its directory.
+const SLED_FILES: [&str; 2] = ["conf", "db"];
+
+/// The engine whose data `dir` holds, read from its `ENGINE_MARK_FILE`, or else told by its
+/// files: sled's `conf` and `db` or the `.log` files of `KvStore`.
+/// `None` for a missing directory or one holding neither.
+pub fn detect_engine(dir: &Path) -> Result<Option<EngineType>> {
+    match std::fs::read_to_string(dir.join(ENGINE_MARK_FILE)) {
+        // An empty mark was created but never written, the files may still tell.
+        Ok(mark) if !mark.trim().is_empty() => {
+            return EngineType::from_str(mark.trim())
+                .map(Some)
+                .with_context(|| format!("Unrecognized engine mark in {:?}.", dir));
+        }
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut sled_files = 0;
+    let mut log_files = false;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if SLED_FILES.contains(&name) {
+            sled_files += 1;
+        } else if path.extension() == Some("log".as_ref()) {
+            log_files = true;
+        }
+    }
+    match (sled_files == SLED_FILES.len(), log_files) {
+        (true, true) => bail!("Both sled and kvs files in {:?}.", dir),
+        (true, false) => Ok(Some(EngineType::Sled)),
+        (false, true) => Ok(Some(EngineType::Kvs)),
+        (false, false) => Ok(None),
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Bits per expected key, with `HASHES` probes about 1% false positives.
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+const MIN_CAPACITY: usize = 1024;
+
+/// Set of keys answering "maybe present" or "surely absent".
+/// Keys are never taken out, removed ones only cost false positives until the next rebuild.
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `capacity` keys.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY);
+        let words = capacity * BITS_PER_KEY / 64 + 1;
+        Self {
+            bits: vec![0; words],
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.probes(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// `false` only if `key` was never inserted.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.probes(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// More keys than it was sized for, false positives climb.
+    pub fn is_full(&self) -> bool {
+        self.len > self.capacity
+    }
+
+    /// Keys inserted so far, the removed ones included.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn probes(&self, key: &str) -> impl Iterator<Item = usize> {
+        // Double hashing, the probes are `h1 + i * h2`.
+        let h1 = hash(key, 0);
+        let h2 = hash(key, 1) | 1;
+        let total_bits = (self.bits.len() * 64) as u64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % total_bits) as usize)
+    }
+}
+
+fn hash(key: &str, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Clock, KvStore, KvStoreConfig, Result};
+
+/// How soon writes reach the disk, see `KvStoreBuilder::durability`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Durability {
+    /// Whenever the OS writes its buffers back, or on `flush`.
+    #[default]
+    Os,
+    /// Synced at most this long after being written.
+    Periodic(Duration),
+}
+
+/// Chainable options of a `KvStore`, from `KvStore::builder`.
+/// The ones without a setter are reached through `config`.
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreBuilder {
+    config: KvStoreConfig,
+    data_subdir: Option<PathBuf>,
+}
+
+impl KvStoreBuilder {
+    /// Start from `config` rather than the defaults.
+    pub fn config(mut self, config: KvStoreConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// See `KvStoreConfig::compaction_threshold`.
+    pub fn compaction_threshold(mut self, threshold: usize) -> Self {
+        self.config.compaction_threshold = Some(threshold);
+        self
+    }
+
+    /// See `KvStoreConfig::file_size`.
+    pub fn max_file_size(mut self, bytes: usize) -> Self {
+        self.config.file_size = Some(bytes);
+        self
+    }
+
+    /// Sync the writes as `durability` says, sets `KvStoreConfig::fsync_interval`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.config.fsync_interval = match durability {
+            Durability::Os => None,
+            Durability::Periodic(interval) => Some(interval),
+        };
+        self
+    }
+
+    /// See `KvStoreConfig::read_only`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// See `KvStoreConfig::value_cache_size`.
+    pub fn cache_capacity(mut self, bytes: usize) -> Self {
+        self.config.value_cache_size = Some(bytes);
+        self
+    }
+
+    /// See `KvStoreConfig::clock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = Some(clock);
+        self
+    }
+
+    /// Keep the store in `subdir` of the directory given to `open`.
+    pub fn data_subdir(mut self, subdir: impl Into<PathBuf>) -> Self {
+        self.data_subdir = Some(subdir.into());
+        self
+    }
+
+    /// Open the store in `dir`.
+    pub fn open(self, dir: impl Into<PathBuf>) -> Result<KvStore> {
+        let dir = dir.into();
+        let dir = match self.data_subdir {
+            Some(subdir) => dir.join(subdir),
+            None => dir,
+        };
+        KvStore::open_with_config(dir, self.config)
+    }
+}
@@ -0,0 +1,22 @@
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the time stamped on the records, see `KvStoreConfig::clock`.
+/// Tests can pass one they move forward themselves.
+pub trait Clock: Debug + Send + Sync {
+    /// Unix millis now.
+    fn now(&self) -> u64;
+}
+
+/// The wall clock, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
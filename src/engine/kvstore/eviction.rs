@@ -0,0 +1,128 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// Bound on a store used as a cache, see `KvStoreConfig::capacity`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capacity {
+    /// At most this many keys.
+    Keys(usize),
+    /// At most this many bytes of keys and values.
+    Bytes(u64),
+}
+
+/// Which key goes first when the capacity is exceeded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EvictionPolicy {
+    /// The least recently read or written.
+    #[default]
+    Lru,
+    /// The least often read or written, the least recently one among equals.
+    Lfu,
+}
+
+#[derive(Debug)]
+struct Entry {
+    uses: u64,
+    last_use: u64,
+    size: u64,
+}
+
+/// Tracks the use of every live key to pick the ones to evict.
+#[derive(Debug)]
+pub struct Evictor {
+    capacity: Capacity,
+    policy: EvictionPolicy,
+    clock: u64,
+    entries: HashMap<String, Entry>,
+    /// `(rank, last_use, key)`, the first one is evicted first.
+    order: BTreeSet<(u64, u64, String)>,
+    bytes: u64,
+}
+
+impl Evictor {
+    pub fn new(capacity: Capacity, policy: EvictionPolicy) -> Self {
+        Self {
+            capacity,
+            policy,
+            clock: 0,
+            entries: HashMap::new(),
+            order: BTreeSet::new(),
+            bytes: 0,
+        }
+    }
+
+    /// `key` was written with a value of `value_len` bytes.
+    pub fn on_write(&mut self, key: &str, value_len: usize) {
+        let size = (key.len() + value_len) as u64;
+        let uses = self.unlink(key).map_or(0, |entry| entry.uses);
+        self.link(key, uses, size);
+    }
+
+    /// `key` was read.
+    pub fn touch(&mut self, key: &str) {
+        if let Some(entry) = self.unlink(key) {
+            self.link(key, entry.uses, entry.size);
+        }
+    }
+
+    /// `key` was removed.
+    pub fn forget(&mut self, key: &str) {
+        self.unlink(key);
+    }
+
+    /// The key to evict while over capacity, never the one used last,
+    /// otherwise LFU would evict every new key right away.
+    pub fn victim(&self) -> Option<&str> {
+        let over = match self.capacity {
+            Capacity::Keys(keys) => self.entries.len() > keys,
+            Capacity::Bytes(bytes) => self.bytes > bytes,
+        };
+        if !over {
+            return None;
+        }
+        self.order
+            .iter()
+            .find(|(_, last_use, _)| *last_use != self.clock)
+            .map(|(_, _, key)| key.as_str())
+    }
+
+    /// The key to evict first to make room for a new one of `size` bytes,
+    /// whichever was used last.
+    pub fn victim_to_fit(&self, size: u64) -> Option<&str> {
+        let full = match self.capacity {
+            Capacity::Keys(keys) => self.entries.len() >= keys,
+            Capacity::Bytes(bytes) => self.bytes + size > bytes,
+        };
+        if !full {
+            return None;
+        }
+        self.order.iter().next().map(|(_, _, key)| key.as_str())
+    }
+
+    fn link(&mut self, key: &str, uses: u64, size: u64) {
+        self.clock += 1;
+        let entry = Entry {
+            uses: uses + 1,
+            last_use: self.clock,
+            size,
+        };
+        self.order
+            .insert((self.rank(&entry), entry.last_use, key.to_owned()));
+        self.bytes += size;
+        self.entries.insert(key.to_owned(), entry);
+    }
+
+    fn unlink(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.order
+            .remove(&(self.rank(&entry), entry.last_use, key.to_owned()));
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn rank(&self, entry: &Entry) -> u64 {
+        match self.policy {
+            EvictionPolicy::Lru => 0,
+            EvictionPolicy::Lfu => entry.uses,
+        }
+    }
+}
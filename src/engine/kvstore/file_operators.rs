@@ -1,25 +1,54 @@
+use std::fmt::{Debug, Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::io::{self, Write};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
 use std::path::PathBuf;
 
-use anyhow::Context;
+use anyhow::{anyhow, bail, Context};
 
 use crate::engine::kvstore::kvstore::CommandPosition;
 use crate::engine::kvstore::Command;
 
 use super::Result;
 
+/// Id of a log file.
 pub type FileID = usize;
 
+/// Byte offset of a command inside a log file.
 pub type FileOffset = u64;
 
+/// Capacity of the read buffers unless configured, the same as `BufReader::new`.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Longest record a `CommandIter` reads unless configured, 1GB.
+pub const DEFAULT_MAX_RECORD_LEN: u64 = 1 << 30;
+
+/// Why a line of a log file is no record, see `FileReader::scan_records`.
+#[derive(Debug, PartialEq)]
+pub enum BadRecord {
+    /// A last line missing its newline, a torn write.
+    Truncated,
+    /// A whole line which doesn't decode, with the reason.
+    Corrupt(String),
+}
+
+impl Display for BadRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BadRecord::Truncated => write!(f, "Record cut short."),
+            BadRecord::Corrupt(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
 /// Buggy 点，每次读取同一个文件都需要重新打开，需要优化
 #[derive(Debug)]
 pub struct FileReader {
     reader: BufReader<File>,
     file_id: FileID,
     file_path: PathBuf,
+    buffer_size: usize,
+    max_record_len: u64,
 }
 
 impl Clone for FileReader {
@@ -27,55 +56,181 @@ impl Clone for FileReader {
         let reader = OpenOptions::new()
             .read(true)
             .open(&self.file_path)
-            .map(|fp| BufReader::new(fp))
+            .map(|fp| BufReader::with_capacity(self.buffer_size, fp))
             .expect(&format!("Failed to open file {:?}", self.file_path));
         Self {
             reader,
             file_id: self.file_id,
             file_path: self.file_path.clone(),
+            buffer_size: self.buffer_size,
+            max_record_len: self.max_record_len,
         }
     }
 }
 
 impl FileReader {
-    pub fn open(dir: impl Into<PathBuf>, id: FileID) -> Result<Self> {
+    /// Open with read buffers of `buffer_size` bytes, fewer reads for large records.
+    pub fn open_with_buffer_size(
+        dir: impl Into<PathBuf>,
+        id: FileID,
+        buffer_size: usize,
+    ) -> Result<Self> {
         let path_buf = file_path_from_id(id, dir);
         let reader = OpenOptions::new()
             .read(true)
             .open(&path_buf)
-            .map(|fp| BufReader::new(fp))?;
+            .map(|fp| BufReader::with_capacity(buffer_size, fp))?;
         Ok(Self {
             reader,
             file_id: id,
             file_path: path_buf,
+            buffer_size,
+            max_record_len: DEFAULT_MAX_RECORD_LEN,
         })
     }
 
+    /// Fail iterating the commands at a record longer than `len` bytes rather than read it.
+    pub fn set_max_record_len(&mut self, len: u64) {
+        self.max_record_len = len;
+    }
+
     pub fn readline_at(&mut self, pos: FileOffset) -> Result<String> {
         self.reader.seek(SeekFrom::Start(pos))?;
-        let mut ret = String::new();
-        self.reader
-            .read_line(&mut ret)
-            .with_context(|| "Error to get line.")
-            .and(Ok(ret))
+        read_record(&mut self.reader, self.file_id, pos)
     }
     pub fn query_command(&self, pos: FileOffset) -> Result<Command> {
-        let mut buf_reader = FileReader::clone(self).reader;
+        let mut buf_reader = self.reopen()?;
         buf_reader.seek(SeekFrom::Start(pos))?;
-        let mut json = String::new();
-        buf_reader
-            .read_line(&mut json)
-            .with_context(|| "Error to get line.")?;
-        Ok(serde_json::from_str::<Command>(json.trim())?)
+        let json = read_record(&mut buf_reader, self.file_id, pos)?;
+        Command::decode(json.trim().as_bytes()).with_context(|| {
+            format!(
+                "Corrupt record in log file {} at offset {}.",
+                self.file_id, pos
+            )
+        })
+    }
+
+    /// Length of the records at `offsets`, newlines included, and of the chunks of the
+    /// chunked ones, read through a single handle.
+    pub fn records_len(&self, offsets: &[FileOffset]) -> Result<u64> {
+        let mut buf_reader = self.reopen()?;
+        let mut len = 0;
+        for &pos in offsets {
+            buf_reader.seek(SeekFrom::Start(pos))?;
+            let json = read_record(&mut buf_reader, self.file_id, pos)?;
+            len += json.len() as u64;
+            let command = Command::decode(json.trim().as_bytes()).with_context(|| {
+                format!(
+                    "Corrupt record in log file {} at offset {}.",
+                    self.file_id, pos
+                )
+            })?;
+            if let Command::Chunked { chunks, .. } = command {
+                for chunk in chunks {
+                    buf_reader.seek(SeekFrom::Start(chunk))?;
+                    len += read_record(&mut buf_reader, self.file_id, chunk)?.len() as u64;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    pub fn command_iter(&self) -> Result<CommandIter> {
+        self.command_iter_from(0)
     }
 
-    pub fn command_iter(&self) -> impl Iterator<Item = (Command, CommandPosition)> {
-        let mut buf_reader = FileReader::clone(self).reader;
-        buf_reader.seek(SeekFrom::Start(0)).unwrap();
-        CommandIter {
+    /// Iterate the commands starting at `pos`, up to the first corrupt one.
+    pub fn command_iter_from(&self, pos: FileOffset) -> Result<CommandIter> {
+        let mut buf_reader = self.reopen()?;
+        buf_reader.seek(SeekFrom::Start(pos))?;
+        Ok(CommandIter {
             reader: buf_reader,
             id: self.file_id,
+            offset: pos,
+            max_record_len: self.max_record_len,
+            done: false,
+        })
+    }
+
+    /// Reassemble the value of `key` from its `Command::Chunk` records at `offsets`.
+    pub fn read_chunks(&self, key: &str, offsets: &[FileOffset]) -> Result<String> {
+        let mut buf_reader = self.reopen()?;
+        let mut value = String::new();
+        for &pos in offsets {
+            buf_reader.seek(SeekFrom::Start(pos))?;
+            let json = read_record(&mut buf_reader, self.file_id, pos)?;
+            match Command::decode(json.trim().as_bytes()) {
+                Ok(Command::Chunk {
+                    key: chunk_key,
+                    data,
+                }) if chunk_key == key => value.push_str(&data),
+                _ => bail!(
+                    "No chunk of {} in log file {} at offset {}.",
+                    key,
+                    self.file_id,
+                    pos
+                ),
+            }
         }
+        Ok(value)
+    }
+
+    /// Read the whole file once, leaving it in the OS page cache.
+    pub fn read_through(&self) -> Result<()> {
+        io::copy(&mut self.reopen()?, &mut io::sink())?;
+        Ok(())
+    }
+
+    /// A reader of its own, so concurrent reads don't move each other's cursor.
+    fn reopen(&self) -> Result<BufReader<File>> {
+        OpenOptions::new()
+            .read(true)
+            .open(&self.file_path)
+            .map(|fp| BufReader::with_capacity(self.buffer_size, fp))
+            .with_context(|| format!("Failed to open file {:?}", self.file_path))
+    }
+
+    /// Every line of the file with its offset, parsed or with the reason it is no record.
+    pub fn scan_records(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(FileOffset, std::result::Result<Command, BadRecord>)>>>
+    {
+        let mut buf_reader = self.reopen()?;
+        let mut offset = 0;
+        Ok(std::iter::from_fn(move || {
+            let mut line = Vec::new();
+            let read = match buf_reader.read_until(b'\n', &mut line) {
+                Ok(0) => return None,
+                Ok(read) => read,
+                Err(e) => return Some(Err(anyhow::Error::from(e).context("Failed to read log."))),
+            };
+            let parsed = if line.ends_with(b"\n") {
+                Command::decode(&line).map_err(|e| BadRecord::Corrupt(e.to_string()))
+            } else {
+                Err(BadRecord::Truncated)
+            };
+            let record = (offset, parsed);
+            offset += read as FileOffset;
+            Some(Ok(record))
+        }))
+    }
+
+    /// Cut the file down to `len` bytes.
+    pub fn truncate(&self, len: FileOffset) -> Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.file_path)
+            .and_then(|file| file.set_len(len))
+            .with_context(|| format!("Failed to truncate file {:?}", self.file_path))
+    }
+
+    pub fn file_id(&self) -> FileID {
+        self.file_id
+    }
+
+    /// Size of the underlying file in bytes.
+    pub fn file_size(&self) -> Result<u64> {
+        Ok(self.reader.get_ref().metadata()?.len())
     }
     pub fn remove_file(self) -> Result<()> {
         std::fs::remove_file(&self.file_path)
@@ -83,42 +238,148 @@ impl FileReader {
     }
 }
 
+/// Read the line at the current position of `reader`, which must be a whole record.
+fn read_record(reader: &mut impl BufRead, file_id: FileID, pos: FileOffset) -> Result<String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .with_context(|| format!("Failed to read log file {} at offset {}.", file_id, pos))?;
+    if line.is_empty() {
+        bail!("No record in log file {} at offset {}.", file_id, pos)
+    }
+    Ok(line)
+}
+
+/// The commands of a log file in order.
+/// Stops after yielding the error of a corrupt or too long record, and before a last record
+/// without its newline: a torn write, or one still being written.
 pub struct CommandIter {
     reader: BufReader<File>,
     id: FileID,
+    offset: FileOffset,
+    max_record_len: u64,
+    done: bool,
+}
+
+impl CommandIter {
+    /// Where the next record starts, past the last one yielded.
+    pub fn offset(&self) -> FileOffset {
+        self.offset
+    }
 }
 
 impl Iterator for CommandIter {
-    type Item = (Command, CommandPosition);
+    type Item = Result<(Command, CommandPosition)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let pos = self.reader.stream_position().ok();
-        pos.and_then(|pos| {
-            let mut buf = String::new();
-            self.reader
-                .read_line(&mut buf)
-                .context("")
-                .and_then(|_| {
-                    serde_json::from_str::<Command>(&buf)
-                        .with_context(|| format!("Failed to parse json"))
-                })
-                .ok()
-                .map(|cmd| {
-                    (
-                        cmd,
-                        CommandPosition {
-                            file_id: self.id,
-                            pos,
-                        },
-                    )
-                })
-        })
+        if self.done {
+            return None;
+        }
+        let pos = self.offset;
+        let mut line = Vec::new();
+        let mut bounded = (&mut self.reader).take(self.max_record_len.saturating_add(1));
+        let parsed = match bounded.read_until(b'\n', &mut line) {
+            // Garbage without a newline would otherwise be read into memory whole.
+            Ok(read) if read as u64 > self.max_record_len => {
+                self.done = true;
+                return Some(Err(anyhow!(
+                    "Record in log file {} at offset {} is longer than {} bytes.",
+                    self.id,
+                    pos,
+                    self.max_record_len
+                )));
+            }
+            Ok(_) if !line.ends_with(b"\n") => {
+                self.done = true;
+                return None;
+            }
+            Ok(read) => Command::decode(&line).map(|cmd| (cmd, read)),
+            Err(e) => {
+                self.done = true;
+                return Some(Err(anyhow::Error::from(e).context(format!(
+                    "Failed to read log file {} at offset {}.",
+                    self.id, pos
+                ))));
+            }
+        };
+        match parsed {
+            Ok((cmd, read)) => {
+                self.offset += read as FileOffset;
+                let ts = cmd.ts();
+                Some(Ok((
+                    cmd,
+                    CommandPosition {
+                        file_id: self.id,
+                        pos,
+                        ts,
+                    },
+                )))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.context(format!(
+                    "Corrupt record in log file {} at offset {}.",
+                    self.id, pos
+                ))))
+            }
+        }
+    }
+}
+
+/// What `FileWriter` appends onto, a plain `File` except in tests injecting failures.
+pub(crate) trait LogFile: Write + Seek + Send + Sync + Debug {
+    fn set_len(&self, size: u64) -> io::Result<()>;
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Stands in for the active log file of a read-only store, refusing every write.
+#[derive(Debug)]
+struct ReadOnlyLog {
+    len: u64,
+}
+
+impl Write for ReadOnlyLog {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "The store is opened read-only.",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ReadOnlyLog {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+impl LogFile for ReadOnlyLog {
+    fn set_len(&self, _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct FileWriter {
-    pub(crate) file: File,
+    pub(crate) file: Box<dyn LogFile>,
     pub(crate) file_id: FileID,
     pub total_size: usize,
 }
@@ -126,19 +387,30 @@ pub(crate) struct FileWriter {
 impl FileWriter {
     pub fn open(dir: impl Into<PathBuf>, id: FileID) -> Result<Self> {
         let dir_path = dir.into();
+        let path = file_path_from_id(id, &dir_path);
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(file_path_from_id(id, &dir_path))
-            .expect(&format!(
-                "Failed to open file {:?}",
-                file_path_from_id(id, &dir_path)
-            ));
-        file.seek(SeekFrom::End(0))?;
+            .open(&path)
+            .with_context(|| format!("Failed to open file {:?}", path))?;
+        let len = file.seek(SeekFrom::End(0))?;
         Ok(Self {
-            file,
+            file: Box::new(file),
             file_id: id,
-            total_size: 0,
+            total_size: len as usize,
+        })
+    }
+
+    /// A writer positioned at the end of the existing file `id`, failing every append.
+    pub fn read_only(dir: impl Into<PathBuf>, id: FileID) -> Result<Self> {
+        let path = file_path_from_id(id, dir);
+        let len = std::fs::metadata(&path)
+            .with_context(|| format!("Failed to open file {:?}", path))?
+            .len();
+        Ok(Self {
+            file: Box::new(ReadOnlyLog { len }),
+            file_id: id,
+            total_size: len as usize,
         })
     }
     pub fn flush(&mut self) -> Result<()> {
@@ -150,41 +422,81 @@ impl FileWriter {
         })
     }
 
-    pub fn append_serialized_command(&mut self, str: &str) -> Result<CommandPosition> {
-        let pos = self.file.stream_position()?;
-        let size = self
-            .file
-            .write(str.as_bytes())
-            .context("Failed to write str")?;
-        self.total_size += size;
+    /// Make the written records durable.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file
+            .sync_data()
+            .with_context(|| format!("Failed to sync file, file_id: {}", self.file_id))
+    }
+
+    /// Where the next record will be written.
+    pub fn position(&mut self) -> Result<CommandPosition> {
         Ok(CommandPosition {
             file_id: self.file_id,
-            pos,
+            pos: self.file.stream_position()?,
+            ts: None,
         })
     }
 
+    pub fn append_serialized_command(&mut self, str: &str) -> Result<CommandPosition> {
+        self.append_bytes(str.as_bytes())
+    }
+
+    /// Cut the file back to `pos`, dropping the records appended since.
+    pub fn truncate(&mut self, pos: FileOffset) -> Result<()> {
+        self.file
+            .set_len(pos)
+            .and_then(|_| self.file.seek(SeekFrom::Start(pos)))
+            .with_context(|| {
+                format!(
+                    "Failed to truncate file, file_id: {}, pos: {}",
+                    self.file_id, pos
+                )
+            })?;
+        self.total_size = pos as usize;
+        Ok(())
+    }
+
     pub fn get_total_size(&self) -> usize {
         self.total_size
     }
 
     pub fn append_command(&mut self, command: &Command) -> Result<CommandPosition> {
-        let mut record_string = serde_json::to_string(command)
+        let mut record_string = command
+            .encode()
             .with_context(|| format!("Failed to serialize Command. {:?}", command))?;
         record_string.push('\n');
-        let stream_pos = self
+        Ok(CommandPosition {
+            ts: command.ts(),
+            ..self.append_bytes(record_string.as_bytes())?
+        })
+    }
+
+    /// Write a whole record, or cut off what was written of it and report the error,
+    /// e.g. when the disk is full.
+    fn append_bytes(&mut self, record: &[u8]) -> Result<CommandPosition> {
+        let pos = self
             .file
             .stream_position()
             .context("Failed to get stream position of new record.")?;
-        self.file
-            .write(record_string.as_bytes())
-            .with_context(|| format!("Failed to write file."))
-            .map(|cnt| {
-                self.total_size += cnt;
-            })
-            .and(Ok(CommandPosition {
-                file_id: self.file_id,
-                pos: stream_pos,
-            }))
+        if let Err(e) = self.file.write_all(record) {
+            self.file
+                .set_len(pos)
+                .and_then(|_| self.file.seek(SeekFrom::Start(pos)))
+                .with_context(|| {
+                    format!(
+                        "Failed to remove a partial record, file_id: {}, pos: {}",
+                        self.file_id, pos
+                    )
+                })?;
+            return Err(anyhow::Error::from(e).context("Failed to write file."));
+        }
+        self.total_size += record.len();
+        Ok(CommandPosition {
+            file_id: self.file_id,
+            pos,
+            ts: None,
+        })
     }
 }
 
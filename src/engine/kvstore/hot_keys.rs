@@ -0,0 +1,111 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+
+use super::Result;
+
+/// Keys counted at most, past it the least read half is forgotten.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+thread_local! {
+    /// Xorshift state, seeded differently on every thread.
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+fn next_random() -> u64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        x
+    })
+}
+
+/// Reads of each key, sampled: one read in `rate` at random is counted, as `rate` reads.
+#[derive(Debug)]
+pub struct HotKeys {
+    rate: u64,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl HotKeys {
+    /// Count one read in `rate`, on top of `counts` saved earlier.
+    pub fn new(rate: u64, counts: HashMap<String, u64>) -> Self {
+        Self {
+            rate: rate.max(1),
+            counts: Mutex::new(counts),
+        }
+    }
+
+    /// Count a read of `key` if it is sampled, the others cost a thread local draw.
+    pub fn record(&self, key: &str) -> Result<()> {
+        if self.rate > 1 && next_random() % self.rate != 0 {
+            return Ok(());
+        }
+        let mut counts = self
+            .counts
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire hot keys lock."))?;
+        match counts.get_mut(key) {
+            Some(count) => *count += self.rate,
+            None => {
+                if counts.len() >= MAX_TRACKED_KEYS {
+                    forget_coldest(&mut counts);
+                }
+                counts.insert(key.to_owned(), self.rate);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads counted so far of each key, as saved on close.
+    pub fn counts(&self) -> Result<HashMap<String, u64>> {
+        self.counts
+            .lock()
+            .map(|counts| counts.clone())
+            .map_err(|_| anyhow!("Failed to acquire hot keys lock."))
+    }
+}
+
+/// Drop the least read half of the keys, whichever of equally read ones.
+fn forget_coldest(counts: &mut HashMap<String, u64>) {
+    let mut keys: Vec<(u64, String)> = counts.drain().map(|(key, n)| (n, key)).collect();
+    let half = keys.len() / 2;
+    keys.select_nth_unstable_by_key(half, |(n, _)| *n);
+    counts.extend(keys.drain(half..).map(|(n, key)| (key, n)));
+}
+
+/// The `n` most read of `counts`, most read first and ties in key order.
+pub fn top_keys(counts: HashMap<String, u64>, n: usize) -> Vec<(String, u64)> {
+    let mut keys: Vec<_> = counts.into_iter().collect();
+    keys.sort_unstable_by(|(a, a_reads), (b, b_reads)| b_reads.cmp(a_reads).then(a.cmp(b)));
+    keys.truncate(n);
+    keys
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tracking too many equally read keys forgets half of them, not the new one.
+    #[test]
+    fn forget_coldest_half() -> Result<()> {
+        let hot_keys = HotKeys::new(1, HashMap::new());
+        for i in 0..MAX_TRACKED_KEYS {
+            hot_keys.record(&format!("key{}", i))?;
+        }
+        hot_keys.record("key1")?;
+        hot_keys.record("new")?;
+        let counts = hot_keys.counts()?;
+        assert_eq!(counts.len(), MAX_TRACKED_KEYS / 2 + 1);
+        assert_eq!(counts.get("key1"), Some(&2));
+        assert_eq!(counts.get("new"), Some(&1));
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use super::kvstore::CommandPosition;
+use super::Result;
+
+pub(crate) type Entry = (String, CommandPosition);
+
+type Entries<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// A key with its position, `None` for a key removed since an older run was written.
+type Slot = (String, Option<CommandPosition>);
+
+type Slots<'a> = Box<dyn Iterator<Item = Result<Slot>> + 'a>;
+
+/// Map from keys to the position of their latest insertion.
+pub(crate) trait Index: Send + Sync {
+    /// Position of the latest insertion of `key`.
+    fn get(&self, key: &str) -> Result<Option<CommandPosition>>;
+    /// Point `key` at `pos`, returning the previous position.
+    fn insert(&mut self, key: String, pos: CommandPosition) -> Result<Option<CommandPosition>>;
+    /// Forget `key`, returning its last position.
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPosition>>;
+    /// Entries whose key is not less than `start`, in key order.
+    fn range_from(&self, start: &str) -> Result<Entries<'_>>;
+    /// Every entry, in key order.
+    fn iter(&self) -> Result<Entries<'_>> {
+        self.range_from("")
+    }
+    /// Number of keys.
+    fn len(&self) -> Result<usize> {
+        self.iter()?
+            .try_fold(0, |len, entry| entry.map(|_| len + 1))
+    }
+    /// The largest key.
+    fn last_key(&self) -> Result<Option<String>> {
+        self.iter()?
+            .try_fold(None, |_, entry| entry.map(|(key, _)| Some(key)))
+    }
+}
+
+impl Index for BTreeMap<String, CommandPosition> {
+    fn get(&self, key: &str) -> Result<Option<CommandPosition>> {
+        Ok(BTreeMap::get(self, key).cloned())
+    }
+
+    fn insert(&mut self, key: String, pos: CommandPosition) -> Result<Option<CommandPosition>> {
+        Ok(BTreeMap::insert(self, key, pos))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPosition>> {
+        Ok(BTreeMap::remove(self, key))
+    }
+
+    fn range_from(&self, start: &str) -> Result<Entries<'_>> {
+        Ok(Box::new(
+            self.range::<str, _>((Bound::Included(start), Bound::Unbounded))
+                .map(|(key, pos)| Ok((key.clone(), pos.clone()))),
+        ))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(BTreeMap::len(self))
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        Ok(self.keys().next_back().cloned())
+    }
+}
+
+/// Keep one key in memory for every `SPARSE_STEP` entries of a sorted run.
+const SPARSE_STEP: usize = 64;
+
+/// Index keeping at most `threshold` recently written keys in memory,
+/// the others are spilled into sorted runs on disk.
+///
+/// A spill writes the hot keys as a new run, merged with the newest runs no more than
+/// twice its size, so run sizes grow geometrically and each key is rewritten
+/// a logarithmic number of times.
+pub(crate) struct SpillIndex {
+    hot: BTreeMap<String, Option<CommandPosition>>,
+    /// Oldest first, a key in a newer run shadows it in the older ones.
+    runs: Vec<SortedRun>,
+    path: PathBuf,
+    threshold: usize,
+    next_run_id: u64,
+}
+
+impl SpillIndex {
+    pub fn new(path: impl Into<PathBuf>, threshold: usize) -> Self {
+        Self {
+            hot: BTreeMap::new(),
+            runs: Vec::new(),
+            path: path.into(),
+            threshold,
+            next_run_id: 0,
+        }
+    }
+
+    fn spill_if_needed(&mut self) -> Result<()> {
+        if self.hot.len() <= self.threshold {
+            return Ok(());
+        }
+        let mut len = self.hot.len();
+        let mut merged = self.runs.len();
+        while merged > 0 && self.runs[merged - 1].len <= 2 * len {
+            merged -= 1;
+            len += self.runs[merged].len;
+        }
+        let mut slots: Slots<'_> = Box::new(
+            self.hot
+                .iter()
+                .map(|(key, pos)| Ok((key.clone(), pos.clone()))),
+        );
+        for run in self.runs[merged..].iter().rev() {
+            slots = Box::new(MergeIter::new(slots, run.iter_from("")?));
+        }
+        if merged == 0 {
+            // Nothing older is left to hide.
+            slots = Box::new(slots.filter(|slot| !matches!(slot, Ok((_, None)))));
+        }
+        let path = self.path.with_extension(self.next_run_id.to_string());
+        let run = SortedRun::write(&path, slots)?;
+        self.next_run_id += 1;
+        for old in self.runs.drain(merged..) {
+            let _ = fs::remove_file(&old.path);
+        }
+        self.runs.push(run);
+        self.hot.clear();
+        Ok(())
+    }
+
+    /// Every key not less than `start` in the hot keys and the runs, removed ones included.
+    fn slots_from(&self, start: &str) -> Result<Slots<'_>> {
+        let mut slots: Slots<'_> = Box::new(std::iter::empty());
+        for run in &self.runs {
+            slots = Box::new(MergeIter::new(run.iter_from(start)?, slots));
+        }
+        let hot = self
+            .hot
+            .range::<str, _>((Bound::Included(start), Bound::Unbounded))
+            .map(|(key, pos)| Ok((key.clone(), pos.clone())));
+        Ok(Box::new(MergeIter::new(hot, slots)))
+    }
+}
+
+impl Index for SpillIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandPosition>> {
+        if let Some(pos) = self.hot.get(key) {
+            return Ok(pos.clone());
+        }
+        for run in self.runs.iter().rev() {
+            if let Some(pos) = run.get(key)? {
+                return Ok(pos);
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert(&mut self, key: String, pos: CommandPosition) -> Result<Option<CommandPosition>> {
+        let prev = self.get(&key)?;
+        self.hot.insert(key, Some(pos));
+        self.spill_if_needed()?;
+        Ok(prev)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPosition>> {
+        let prev = self.get(key)?;
+        if prev.is_some() {
+            if self.runs.is_empty() {
+                self.hot.remove(key);
+            } else {
+                self.hot.insert(key.to_owned(), None);
+                self.spill_if_needed()?;
+            }
+        }
+        Ok(prev)
+    }
+
+    fn range_from(&self, start: &str) -> Result<Entries<'_>> {
+        Ok(Box::new(self.slots_from(start)?.filter_map(
+            |slot| match slot {
+                Ok((key, Some(pos))) => Some(Ok((key, pos))),
+                Ok((_, None)) => None,
+                Err(e) => Some(Err(e)),
+            },
+        )))
+    }
+}
+
+impl Drop for SpillIndex {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = fs::remove_file(&run.path);
+        }
+    }
+}
+
+/// Slots sorted by key, one json line each.
+struct SortedRun {
+    path: PathBuf,
+    /// Every `SPARSE_STEP`th key with the offset of its line.
+    sparse: Vec<(String, u64)>,
+    len: usize,
+}
+
+impl SortedRun {
+    fn write(path: &Path, slots: impl Iterator<Item = Result<Slot>>) -> Result<Self> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut sparse = Vec::new();
+        let mut offset = 0;
+        let mut len = 0;
+        for slot in slots {
+            let slot = slot?;
+            if len % SPARSE_STEP == 0 {
+                sparse.push((slot.0.clone(), offset));
+            }
+            let line = serde_json::to_string(&slot)?;
+            writeln!(writer, "{}", line)?;
+            offset += line.len() as u64 + 1;
+            len += 1;
+        }
+        writer.flush()?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace the index file {:?}", path))?;
+        Ok(Self {
+            path: path.to_owned(),
+            sparse,
+            len,
+        })
+    }
+
+    /// The slot of `key`, `None` if this run doesn't have it.
+    fn get(&self, key: &str) -> Result<Option<Option<CommandPosition>>> {
+        match self.iter_from(key)?.next() {
+            Some(Ok((found, pos))) if found == key => Ok(Some(pos)),
+            Some(Err(e)) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_from(&self, start: &str) -> Result<Slots<'static>> {
+        // Start from the last sampled key not greater than `start`.
+        let block = match self
+            .sparse
+            .binary_search_by(|(key, _)| key.as_str().cmp(start))
+        {
+            Ok(i) => i,
+            Err(0) => 0,
+            Err(i) => i - 1,
+        };
+        let offset = self.sparse.get(block).map_or(0, |(_, offset)| *offset);
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(offset))?;
+        let start = start.to_owned();
+        let path = self.path.clone();
+        Ok(Box::new(
+            reader
+                .lines()
+                .map(move |line| {
+                    let line = line?;
+                    serde_json::from_str::<Slot>(&line)
+                        .with_context(|| format!("Corrupted index file {:?}", path))
+                })
+                .skip_while(move |slot| matches!(slot, Ok((key, _)) if *key < start)),
+        ))
+    }
+}
+
+/// Merge newer slots over older ones, both in key order. Errors of either side are
+/// passed on as they come.
+struct MergeIter<N: Iterator, O: Iterator> {
+    newer: Peekable<N>,
+    older: Peekable<O>,
+}
+
+impl<N, O> MergeIter<N, O>
+where
+    N: Iterator<Item = Result<Slot>>,
+    O: Iterator<Item = Result<Slot>>,
+{
+    fn new(newer: N, older: O) -> Self {
+        Self {
+            newer: newer.peekable(),
+            older: older.peekable(),
+        }
+    }
+}
+
+impl<N, O> Iterator for MergeIter<N, O>
+where
+    N: Iterator<Item = Result<Slot>>,
+    O: Iterator<Item = Result<Slot>>,
+{
+    type Item = Result<Slot>;
+
+    fn next(&mut self) -> Option<Result<Slot>> {
+        let order = match (self.newer.peek(), self.older.peek()) {
+            (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+            (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+            (None, None) => return None,
+            (Some(Ok((newer, _))), Some(Ok((older, _)))) => newer.cmp(older),
+        };
+        match order {
+            Ordering::Greater => return self.older.next(),
+            Ordering::Equal => {
+                self.older.next();
+            }
+            Ordering::Less => (),
+        }
+        self.newer.next()
+    }
+}
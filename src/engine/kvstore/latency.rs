@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// One bucket per bit length of the durations in nanoseconds.
+const BUCKETS: usize = 64;
+
+/// Operations timed by `Latencies`.
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Get,
+    Set,
+    Remove,
+}
+
+/// Latency of one operation, see `KvStore::latency_report`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpLatency {
+    /// Operations timed.
+    pub count: u64,
+    /// Median, rounded up to a power of two nanoseconds.
+    pub p50: Duration,
+    /// 99th percentile, rounded up to a power of two nanoseconds.
+    pub p99: Duration,
+    /// Slowest one.
+    pub max: Duration,
+}
+
+/// Latency of the operations of a `KvStore` since it was opened,
+/// all zero unless `KvStoreConfig::record_latency` is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyReport {
+    /// Of `get`.
+    pub get: OpLatency,
+    /// Of `set`.
+    pub set: OpLatency,
+    /// Of `remove`.
+    pub remove: OpLatency,
+}
+
+/// Durations counted in buckets of powers of two, each recorded with a few atomic adds.
+#[derive(Debug)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [(); BUCKETS].map(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = (64 - nanos.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Largest duration of the bucket reaching the `quantile` of the counts, at most `max`.
+    fn percentile(&self, quantile: f64, count: u64, max: u64) -> Duration {
+        let rank = ((count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, counted) in self.buckets.iter().enumerate() {
+            seen += counted.load(Ordering::Relaxed);
+            if seen >= rank {
+                let upper = (1u64 << bucket) - 1;
+                return Duration::from_nanos(upper.min(max));
+            }
+        }
+        Duration::from_nanos(max)
+    }
+
+    fn report(&self) -> OpLatency {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return OpLatency::default();
+        }
+        let max = self.max.load(Ordering::Relaxed);
+        OpLatency {
+            count,
+            p50: self.percentile(0.5, count, max),
+            p99: self.percentile(0.99, count, max),
+            max: Duration::from_nanos(max),
+        }
+    }
+}
+
+/// Latency histograms of the operations of a store, shared by its handles.
+#[derive(Debug, Default)]
+pub struct Latencies {
+    get: Histogram,
+    set: Histogram,
+    remove: Histogram,
+}
+
+impl Latencies {
+    pub fn record(&self, op: Op, elapsed: Duration) {
+        match op {
+            Op::Get => &self.get,
+            Op::Set => &self.set,
+            Op::Remove => &self.remove,
+        }
+        .record(elapsed)
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport {
+            get: self.get.report(),
+            set: self.set.report(),
+            remove: self.remove.report(),
+        }
+    }
+}
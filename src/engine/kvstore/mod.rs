@@ -1,14 +1,136 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
 use serde::Serialize;
 
-pub use kvstore::KvStore;
+use value_log::ValuePointer;
 
+pub use builder::{Durability, KvStoreBuilder};
+pub use clock::{Clock, SystemClock};
+pub use eviction::{Capacity, EvictionPolicy};
+pub use file_operators::{FileID, FileOffset};
+pub use kvstore::{
+    AccessHook, Change, CommandSummary, CompactionEstimate, CompactionStats, DumpFormat, EntryMeta,
+    IntegrityIssue, KvStore, KvStoreConfig, SalvageReport, SegmentInfo, StorageStats,
+};
+pub use latency::{LatencyReport, OpLatency};
+
+mod bloom;
+mod builder;
+mod clock;
+mod eviction;
 mod file_operators;
+mod hot_keys;
+mod index;
 mod kvstore;
+mod latency;
+mod value_cache;
+mod value_log;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    Insertion { key: String, value: String },
-    Discard { key: String },
+    Insertion {
+        key: String,
+        value: String,
+        /// Unix millis of the write, missing in records written by older versions.
+        #[serde(default)]
+        ts: Option<u64>,
+    },
+    Discard {
+        key: String,
+    },
+    /// Insertion whose value is kept in the value log.
+    Pointer {
+        key: String,
+        ptr: ValuePointer,
+        #[serde(default)]
+        ts: Option<u64>,
+    },
+    /// Piece of a value too long for one record, see `Command::Chunked`.
+    Chunk {
+        key: String,
+        data: String,
+    },
+    /// Insertion whose value is split into the `Chunk` records at `chunks`, in order,
+    /// written just before it in the same file.
+    Chunked {
+        key: String,
+        chunks: Vec<FileOffset>,
+        len: usize,
+        #[serde(default)]
+        ts: Option<u64>,
+    },
+    /// Leads the `ops` writes of a transaction, which only count once the `Commit`
+    /// following them is written.
+    Begin {
+        ops: usize,
+    },
+    /// Ends the transaction of the last `Begin`.
+    Commit,
+}
+
+/// Schema of the records written now, leading each as `v<version>` before its JSON.
+/// Records written before versioning have no prefix and are of schema 1.
+pub const COMMAND_SCHEMA_VERSION: u32 = 2;
+
+impl Command {
+    /// The record of this command in the current schema, without its newline.
+    fn encode(&self) -> serde_json::Result<String> {
+        Ok(format!(
+            "v{}{}",
+            COMMAND_SCHEMA_VERSION,
+            serde_json::to_string(self)?
+        ))
+    }
+
+    /// Read a record of any schema up to the current one, upgrading older ones.
+    fn decode(record: &[u8]) -> Result<Self> {
+        let (version, json) = split_schema_version(record)?;
+        match version {
+            // Schema 2 only added the prefix, the commands are the same.
+            1 | 2 => Ok(serde_json::from_slice(json)?),
+            _ => bail!(
+                "Record of schema version {}, newer than {} read by this build.",
+                version,
+                COMMAND_SCHEMA_VERSION
+            ),
+        }
+    }
+
+    /// Key the command writes, handed back without a copy, `None` for transaction markers.
+    fn into_key(self) -> Option<String> {
+        match self {
+            Command::Insertion { key, .. }
+            | Command::Discard { key }
+            | Command::Pointer { key, .. }
+            | Command::Chunk { key, .. }
+            | Command::Chunked { key, .. } => Some(key),
+            Command::Begin { .. } | Command::Commit => None,
+        }
+    }
+
+    /// Unix millis of the write, `None` for removals and records written by older versions.
+    fn ts(&self) -> Option<u64> {
+        match self {
+            Command::Insertion { ts, .. }
+            | Command::Pointer { ts, .. }
+            | Command::Chunked { ts, .. } => *ts,
+            Command::Discard { .. }
+            | Command::Chunk { .. }
+            | Command::Begin { .. }
+            | Command::Commit => None,
+        }
+    }
+}
+
+/// Schema version of `record` and the JSON of its command.
+fn split_schema_version(record: &[u8]) -> Result<(u32, &[u8])> {
+    let rest = match record.strip_prefix(b"v") {
+        Some(rest) => rest,
+        None => return Ok((1, record)),
+    };
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    let version = std::str::from_utf8(&rest[..digits])?
+        .parse()
+        .context("Record without its schema version.")?;
+    Ok((version, &rest[digits..]))
 }
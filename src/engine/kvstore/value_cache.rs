@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::eviction::{Capacity, EvictionPolicy, Evictor};
+
+/// Values of recently read keys, shared with the readers instead of copied.
+/// The least recently used ones are dropped to stay within the capacity in bytes.
+#[derive(Debug)]
+pub struct ValueCache {
+    values: HashMap<String, Arc<str>>,
+    evictor: Evictor,
+    capacity: usize,
+}
+
+impl ValueCache {
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self {
+            values: HashMap::new(),
+            evictor: Evictor::new(Capacity::Bytes(bytes as u64), EvictionPolicy::Lru),
+            capacity: bytes,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Arc<str>> {
+        let value = self.values.get(key)?.clone();
+        self.evictor.touch(key);
+        Some(value)
+    }
+
+    /// Value of `key` if cached, without making it recently used.
+    pub fn peek(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| &**value)
+    }
+
+    /// Cache `value` once the values it doesn't fit with are dropped,
+    /// not at all if it is larger than the whole cache.
+    pub fn insert(&mut self, key: &str, value: Arc<str>) {
+        self.invalidate(key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        while let Some(victim) = self.evictor.victim_to_fit(size as u64).map(str::to_owned) {
+            self.invalidate(&victim);
+        }
+        self.evictor.on_write(key, value.len());
+        self.values.insert(key.to_owned(), value);
+    }
+
+    /// Forget every value.
+    pub fn clear(&mut self) {
+        let keys: Vec<_> = self.values.keys().cloned().collect();
+        for key in keys {
+            self.invalidate(&key);
+        }
+    }
+
+    /// Forget `key`, its value changed.
+    pub fn invalidate(&mut self, key: &str) {
+        if self.values.remove(key).is_some() {
+            self.evictor.forget(key);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use super::file_operators::{FileID, FileOffset};
+use super::Result;
+
+/// Where a value kept out of the key log is stored.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ValuePointer {
+    pub file_id: FileID,
+    pub offset: FileOffset,
+    pub len: usize,
+}
+
+/// Files holding large values back to back, referenced by `Command::Pointer` records.
+/// Nothing is created until the first value is appended.
+#[derive(Debug)]
+pub struct ValueLog {
+    dir: PathBuf,
+    active_id: FileID,
+    writer: Option<File>,
+}
+
+impl ValueLog {
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        let active_id = Self::file_ids(&dir).into_iter().max().unwrap_or(0);
+        Self {
+            dir,
+            active_id,
+            writer: None,
+        }
+    }
+
+    pub fn append(&mut self, value: &str) -> Result<ValuePointer> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self.writer.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file_path(&self.dir, self.active_id))?,
+            ),
+        };
+        let offset = writer.seek(SeekFrom::End(0))?;
+        if let Err(e) = writer.write_all(value.as_bytes()) {
+            writer
+                .set_len(offset)
+                .context("Failed to remove a partial value.")?;
+            return Err(anyhow::Error::from(e).context("Failed to write value log."));
+        }
+        Ok(ValuePointer {
+            file_id: self.active_id,
+            offset,
+            len: value.len(),
+        })
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        match &self.writer {
+            Some(writer) => writer
+                .sync_data()
+                .with_context(|| format!("Failed to sync value log {}", self.active_id)),
+            None => Ok(()),
+        }
+    }
+
+    /// Continue on a new file, returning the ids of the previous ones.
+    pub fn roll(&mut self) -> Result<Vec<FileID>> {
+        self.sync()?;
+        let previous = Self::file_ids(&self.dir);
+        self.active_id = previous.iter().max().map_or(0, |id| id + 1);
+        self.writer = None;
+        Ok(previous)
+    }
+
+    pub fn remove_file(&self, id: FileID) -> Result<u64> {
+        let path = file_path(&self.dir, id);
+        let size = fs::metadata(&path)?.len();
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove outdated value log: {:?}", path))?;
+        Ok(size)
+    }
+
+    fn file_ids(dir: &Path) -> Vec<FileID> {
+        fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flat_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension() == Some("vlog".as_ref()))
+                    .flat_map(|path| {
+                        path.file_stem()
+                            .and_then(OsStr::to_str)
+                            .and_then(|stem| stem.parse().ok())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Read the value `ptr` points to in `dir`.
+pub fn read_value(dir: &Path, ptr: &ValuePointer) -> Result<String> {
+    let path = file_path(dir, ptr.file_id);
+    let mut file =
+        File::open(&path).with_context(|| format!("Failed to open value log {:?}", path))?;
+    // Checked before allocating, the length comes from a record which may be corrupt.
+    if !within(ptr, file.metadata()?.len()) {
+        bail!("Value out of the bounds of its value log: {:?}", ptr)
+    }
+    file.seek(SeekFrom::Start(ptr.offset))?;
+    let mut buf = vec![0; ptr.len];
+    file.read_exact(&mut buf)
+        .with_context(|| format!("Failed to read value at {:?}", ptr))?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Whether the value `ptr` refers to lies within its value log file.
+pub fn pointer_in_bounds(dir: &Path, ptr: &ValuePointer) -> bool {
+    fs::metadata(file_path(dir, ptr.file_id))
+        .map(|metadata| within(ptr, metadata.len()))
+        .unwrap_or(false)
+}
+
+fn within(ptr: &ValuePointer, file_size: u64) -> bool {
+    matches!(ptr.offset.checked_add(ptr.len as u64), Some(end) if end <= file_size)
+}
+
+fn file_path(dir: &Path, id: FileID) -> PathBuf {
+    dir.join(format!("{:05}.vlog", id))
+}
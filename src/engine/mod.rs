@@ -1,22 +1,248 @@
 //! Different implement of key-value engine.
-use anyhow::Result;
+use std::panic::{self, AssertUnwindSafe};
 
-pub use kvstore::KvStore;
+use anyhow::{bail, ensure, Result};
+
+use crate::KvError;
+
+pub use detect::{detect_engine, ENGINE_MARK_FILE};
+pub use kvstore::{
+    AccessHook, Capacity, Change, Clock, CommandSummary, CompactionEstimate, CompactionStats,
+    DumpFormat, Durability, EntryMeta, EvictionPolicy, FileID, FileOffset, IntegrityIssue, KvStore,
+    KvStoreBuilder, KvStoreConfig, LatencyReport, OpLatency, SalvageReport, SegmentInfo,
+    StorageStats, SystemClock,
+};
+pub(crate) use sharded::fnv1a;
+pub use sharded::ShardedKvStore;
 pub use sled_store::SledAdapter;
 
+mod detect;
 mod kvstore;
+mod sharded;
 mod sled_store;
 
+/// Pairs read at a time by the methods `KvsEngine` builds on `scan_after`.
+const DEFAULT_PAGE_SIZE: usize = 1024;
+
 /// Trait which Key-Value storage engine should obey.
+/// Only `get`, `set`, `remove` and `scan_after` are required, the others are built on them
+/// and overridden by engines with a cheaper or atomic way.
 pub trait KvsEngine: Clone + Send + 'static {
     /// Get value bind by key.
     fn get(&self, key: &str) -> Result<Option<String>>;
+    /// Hand the value of `key` to `f` rather than a copy of it, `None` if it doesn't exist.
+    fn get_with<R>(&self, key: &str, f: impl FnOnce(&str) -> R) -> Result<Option<R>> {
+        Ok(self.get(key)?.map(|value| f(&value)))
+    }
+    /// Values of `keys` in the same order, `None` for the missing ones.
+    fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+    /// Insert every pair in order, faster than a `set` each for engines locking every write.
+    /// Not atomic, a failure may leave the first pairs written.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(&key, &value)?;
+        }
+        Ok(())
+    }
+    /// Remove every key found, telling for each whether it was. Not atomic unless the engine
+    /// overrides it, a failure may leave the first keys removed.
+    fn remove_batch(&self, keys: &[&str]) -> Result<Vec<bool>> {
+        keys.iter()
+            .map(|key| match self.remove(key) {
+                Ok(()) => Ok(true),
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyNotFound(_))) => Ok(false),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+    /// Byte length of the value of `key`, engines lacking a cheaper way read it whole.
+    fn value_len(&self, key: &str) -> Result<Option<usize>> {
+        Ok(self.get(key)?.map(|value| value.len()))
+    }
     /// Insert a key-value pair.
     fn set(&self, key: &str, value: &str) -> Result<()>;
+    /// `set` taking strings the caller owns, which engines storing them as they are
+    /// keep rather than copy.
+    fn set_owned(&self, key: String, value: String) -> Result<()> {
+        self.set(&key, &value)
+    }
     /// Remove an existing key-value pair or report error.
     fn remove(&self, key: &str) -> Result<()>;
+    /// Overwrite the value of an existing key, `KvError::KeyNotFound` if absent.
+    fn replace(&self, key: &str, value: &str) -> Result<()> {
+        if self.get(key)?.is_none() {
+            return Err(KvError::KeyNotFound(key.to_owned()).into());
+        }
+        self.set(key, value)
+    }
+    /// Count keys starting with `prefix`, without loading their values unless the engine
+    /// has no other way.
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut count = 0;
+        for_each_prefixed(self, prefix, |_| {
+            count += 1;
+            Ok(())
+        })?;
+        Ok(count)
+    }
+    /// Smallest key in lexicographic order, `None` if empty.
+    fn first_key(&self) -> Result<Option<String>> {
+        Ok(self.scan_after(None, 1)?.pop().map(|(key, _)| key))
+    }
+    /// Largest key in lexicographic order, `None` if empty. Engines lacking a cheaper way
+    /// page through every key.
+    fn last_key(&self) -> Result<Option<String>> {
+        let mut last = None;
+        loop {
+            let page = self.scan_after(last.as_deref(), DEFAULT_PAGE_SIZE)?;
+            let full = page.len() == DEFAULT_PAGE_SIZE;
+            if let Some((key, _)) = page.into_iter().last() {
+                last = Some(key);
+            }
+            if !full {
+                return Ok(last);
+            }
+        }
+    }
+    /// Remove every key starting with `prefix`, returning how many were removed.
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut keys = Vec::new();
+        for_each_prefixed(self, prefix, |key| {
+            keys.push(key);
+            Ok(())
+        })?;
+        for key in &keys {
+            self.remove(key)?;
+        }
+        Ok(keys.len())
+    }
+    /// Up to `limit` key-value pairs in key order, the ones past `after` or from the first key,
+    /// to page through the whole store.
+    fn scan_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>>;
+    /// Up to `limit` keys in key order past `after`, or from the first key, and the cursor
+    /// to pass as `after` for the next page, `None` once no key is left.
+    fn keys_paginated(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let entries = self.scan_after(after, limit.saturating_add(1))?;
+        key_page(entries.into_iter().map(|(key, _)| Ok(key)), limit)
+    }
+    /// Replace the value of `key` by what `f` returns from the current one, `None` removes it.
+    /// Engines with a write lock run `f` under it so no other write slips in between,
+    /// the others read then write.
+    fn update(&self, key: &str, f: impl FnOnce(Option<String>) -> Option<String>) -> Result<()> {
+        match f(self.get(key)?) {
+            Some(value) => self.set(key, &value),
+            None => match self.remove(key) {
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyNotFound(_))) => Ok(()),
+                removed => removed,
+            },
+        }
+    }
+    /// Add `suffix` to the end of the value of `key`, an absent key counting as empty.
+    /// Atomic as `update` is, concurrent appends all land.
+    fn append(&self, key: &str, suffix: &str) -> Result<()> {
+        self.update(key, |value| Some(value.unwrap_or_default() + suffix))
+    }
+    /// Writes from the log cursor `from` on, from the oldest kept one if `None`, for replicas.
+    /// Fails with `KvError::CursorCompacted` once the records at `from` were compacted away,
+    /// a write failing to read ends them with its error.
+    fn changes_from(
+        &self,
+        _from: Option<(FileID, FileOffset)>,
+    ) -> Result<Box<dyn Iterator<Item = Result<Change>> + Send>> {
+        bail!("Replication is not supported by this engine.")
+    }
+    /// Apply every write of `ops` in order, or none if one of them fails,
+    /// e.g. removing a missing key. No other write interleaves with them.
+    fn transaction(&self, _ops: Vec<WriteOp>) -> Result<()> {
+        bail!("Transactions are not supported by this engine.")
+    }
     /// Flush all In-mem data into the hard device.
     fn flush(&self) -> Result<()> {
         Ok(())
     }
+    /// Start flushing without waiting for it, engines lacking a background flush just `flush`.
+    fn flush_async(&self) -> Result<()> {
+        self.flush()
+    }
+    /// Flush a last time when done with the engine, reporting what dropping it could only log.
+    fn close(self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// One write of a `KvsEngine::transaction`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    /// Insert or overwrite `key`.
+    Set {
+        /// key
+        key: String,
+        /// new value
+        value: String,
+    },
+    /// Remove `key`, failing the transaction if it does not exist.
+    Remove {
+        /// key
+        key: String,
+    },
+}
+
+/// Call `f` with every key of `engine` starting with `prefix`, in key order, reading them
+/// a page at a time.
+fn for_each_prefixed<E: KvsEngine>(
+    engine: &E,
+    prefix: &str,
+    mut f: impl FnMut(String) -> Result<()>,
+) -> Result<()> {
+    // `scan_after` skips the key it starts after.
+    if engine.get(prefix)?.is_some() {
+        f(prefix.to_owned())?;
+    }
+    let mut after = prefix.to_owned();
+    loop {
+        let page = engine.scan_after(Some(&after), DEFAULT_PAGE_SIZE)?;
+        let full = page.len() == DEFAULT_PAGE_SIZE;
+        for (key, _) in page {
+            if !key.starts_with(prefix) {
+                return Ok(());
+            }
+            after.clone_from(&key);
+            f(key)?;
+        }
+        if !full {
+            return Ok(());
+        }
+    }
+}
+
+/// The first `limit` of `keys` in order and the cursor past them, `None` if none is left.
+pub(crate) fn key_page(
+    keys: impl Iterator<Item = Result<String>>,
+    limit: usize,
+) -> Result<(Vec<String>, Option<String>)> {
+    ensure!(limit > 0, "A page holds at least one key.");
+    let mut page = keys
+        .take(limit.saturating_add(1))
+        .collect::<Result<Vec<_>>>()?;
+    if page.len() <= limit {
+        return Ok((page, None));
+    }
+    page.truncate(limit);
+    let next = page.last().cloned();
+    Ok((page, next))
+}
+
+/// Run an `update` callback, handing its panic back so the caller can release its lock
+/// before unwinding, rather than poisoning it.
+pub(crate) fn call_update(
+    f: impl FnOnce(Option<String>) -> Option<String>,
+    current: Option<String>,
+) -> std::thread::Result<Option<String>> {
+    panic::catch_unwind(AssertUnwindSafe(|| f(current)))
 }
@@ -0,0 +1,163 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use super::{key_page, KvStore, KvsEngine};
+
+/// Keys spread over several `KvStore`s, e.g. in directories on different disks.
+/// A key always goes to the same shard as long as the directories are given in the same order.
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+}
+
+impl ShardedKvStore {
+    /// Open a shard in each of `dirs`, the order and count must stay the same across opens.
+    pub fn open<P: Into<PathBuf>>(dirs: impl IntoIterator<Item = P>) -> Result<Self> {
+        let shards = dirs
+            .into_iter()
+            .map(KvStore::open)
+            .collect::<Result<Vec<_>>>()?;
+        if shards.is_empty() {
+            bail!("At least one shard directory is required.");
+        }
+        Ok(Self { shards })
+    }
+
+    /// Index of the shard `key` belongs to.
+    pub fn shard_of(&self, key: &str) -> usize {
+        (fnv1a(key.as_bytes()) % self.shards.len() as u64) as usize
+    }
+
+    /// Key-value pairs whose key starts with `prefix` over all shards, in key order.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(shard.scan_prefix(prefix)?);
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    /// Compact every shard in turn.
+    pub fn compact(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::compact)
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[self.shard_of(key)]
+    }
+
+    fn fold_shards<T>(&self, f: impl Fn(&KvStore) -> Result<T>) -> Result<Vec<T>> {
+        self.shards.iter().map(f).collect()
+    }
+}
+
+/// 64-bit FNV-1a, unlike the std hasher it is guaranteed to stay the same across releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.shard(key).get(key)
+    }
+
+    fn get_with<R>(&self, key: &str, f: impl FnOnce(&str) -> R) -> Result<Option<R>> {
+        self.shard(key).get_with(key, f)
+    }
+
+    fn value_len(&self, key: &str) -> Result<Option<usize>> {
+        self.shard(key).value_len(key)
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.shard(key).set(key, value)
+    }
+
+    fn set_owned(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set_owned(key, value)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        self.shard(key).remove(key)
+    }
+
+    fn replace(&self, key: &str, value: &str) -> Result<()> {
+        self.shard(key).replace(key, value)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        Ok(self
+            .fold_shards(|shard| shard.count_prefix(prefix))?
+            .into_iter()
+            .sum())
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        Ok(self
+            .fold_shards(KvStore::first_key)?
+            .into_iter()
+            .flatten()
+            .min())
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        Ok(self
+            .fold_shards(KvStore::last_key)?
+            .into_iter()
+            .flatten()
+            .max())
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        Ok(self
+            .fold_shards(|shard| shard.remove_prefix(prefix))?
+            .into_iter()
+            .sum())
+    }
+
+    fn scan_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut entries: Vec<_> = self
+            .fold_shards(|shard| shard.scan_after(after, limit))?
+            .into_iter()
+            .flatten()
+            .collect();
+        entries.sort_unstable();
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    fn keys_paginated(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let pages = self.fold_shards(|shard| shard.keys_paginated(after, limit))?;
+        let more_left = pages.iter().any(|(_, next)| next.is_some());
+        let mut keys: Vec<_> = pages.into_iter().flat_map(|(keys, _)| keys).collect();
+        keys.sort_unstable();
+        let (page, next) = key_page(keys.into_iter().map(Ok), limit)?;
+        // Keys a shard left out come after all it returned.
+        let next = next.or_else(|| page.last().filter(|_| more_left).cloned());
+        Ok((page, next))
+    }
+
+    fn update(&self, key: &str, f: impl FnOnce(Option<String>) -> Option<String>) -> Result<()> {
+        self.shard(key).update(key, f)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::flush)
+    }
+
+    fn flush_async(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::flush_async)
+    }
+
+    fn close(self) -> Result<()> {
+        self.shards.into_iter().try_for_each(KvStore::close)
+    }
+}
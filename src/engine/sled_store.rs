@@ -1,16 +1,33 @@
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::panic;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
 
-use anyhow::bail;
 use anyhow::Context;
-use sled::{Db, IVec};
+use log::error;
+use sled::transaction::{abort, TransactionError};
+use sled::{Batch, Db, IVec};
 
-use crate::KvsEngine;
+use crate::engine::{call_update, key_page, WriteOp};
+use crate::{KvError, KvsEngine};
 
 use anyhow::Result;
 #[derive(Clone)]
 /// Adapter for sled engine.
 pub struct SledAdapter {
     db: Db,
+    /// Held by every write, so `update` callbacks see no concurrent change.
+    writes: Arc<Mutex<()>>,
+    /// Set when `flush` only starts a background flush, see `with_async_flush`.
+    async_flush: bool,
+    flushing: Arc<AtomicBool>,
+    /// Asked for a background flush, which the running one picks up.
+    flush_requested: Arc<AtomicBool>,
+    /// Written since the last flush started.
+    dirty: Arc<AtomicBool>,
 }
 
 impl SledAdapter {
@@ -18,9 +35,28 @@ impl SledAdapter {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         Ok(Self {
             db: sled::open(path.into())?,
+            writes: Arc::new(Mutex::new(())),
+            async_flush: false,
+            flushing: Arc::new(AtomicBool::new(false)),
+            flush_requested: Arc::new(AtomicBool::new(false)),
+            dirty: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Make `flush` return at once and flush on a background thread,
+    /// calls made while one is running are merged into it.
+    pub fn with_async_flush(mut self, async_flush: bool) -> Self {
+        self.async_flush = async_flush;
+        self
+    }
+
+    fn write_lock(&self) -> WriteGuard<'_> {
+        WriteGuard {
+            _lock: self.writes.lock().unwrap(),
+            dirty: &self.dirty,
+        }
+    }
+
     fn ivec_from_str(s: &str) -> IVec {
         IVec::from(s)
     }
@@ -30,6 +66,18 @@ impl SledAdapter {
     }
 }
 
+/// Held during a write, marks the store dirty once the write is done.
+struct WriteGuard<'a> {
+    _lock: MutexGuard<'a, ()>,
+    dirty: &'a AtomicBool,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+}
+
 impl KvsEngine for SledAdapter {
     fn get(&self, key: &str) -> Result<Option<String>> {
         self.db
@@ -38,8 +86,16 @@ impl KvsEngine for SledAdapter {
             .context("Failed to get value.")
     }
 
+    fn value_len(&self, key: &str) -> Result<Option<usize>> {
+        self.db
+            .get(Self::ivec_from_str(key))
+            .map(|x| x.map(|value| value.len()))
+            .context("Failed to get value.")
+    }
+
     fn set(&self, key: &str, value: &str) -> Result<()> {
         let (ikey, ivalue) = (Self::ivec_from_str(key), Self::ivec_from_str(value));
+        let _lock = self.write_lock();
         self.db.insert(ikey, ivalue).map(|_| ()).with_context(|| {
             format!(
                 "Failed to insert value into Sled. key={}, value={}",
@@ -49,13 +105,208 @@ impl KvsEngine for SledAdapter {
     }
 
     fn remove(&self, key: &str) -> Result<()> {
+        let _lock = self.write_lock();
         match self.db.remove(Self::ivec_from_str(key))? {
             Some(_) => Ok(()),
-            None => bail!("Key: {} not found.", key),
+            None => Err(KvError::KeyNotFound(key.to_owned()).into()),
+        }
+    }
+
+    /// Applied as one sled `Batch`, so the removals land together or not at all.
+    fn remove_batch(&self, keys: &[&str]) -> Result<Vec<bool>> {
+        let _lock = self.write_lock();
+        let mut batch = Batch::default();
+        let mut removed = HashSet::new();
+        let mut found = Vec::with_capacity(keys.len());
+        for &key in keys {
+            let exists = !removed.contains(key) && self.db.contains_key(key)?;
+            if exists {
+                batch.remove(Self::ivec_from_str(key));
+                removed.insert(key);
+            }
+            found.push(exists);
+        }
+        self.db
+            .apply_batch(batch)
+            .context("Failed to apply the removals.")?;
+        Ok(found)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.db
+            .scan_prefix(prefix)
+            .try_fold(0, |count, entry| entry.map(|_| count + 1))
+            .context("Failed to scan keys.")
+    }
+
+    fn replace(&self, key: &str, value: &str) -> Result<()> {
+        let value = Self::ivec_from_str(value);
+        let _lock = self.write_lock();
+        match self
+            .db
+            .fetch_and_update(Self::ivec_from_str(key), |old| old.map(|_| value.clone()))?
+        {
+            Some(_) => Ok(()),
+            None => Err(KvError::KeyNotFound(key.to_owned()).into()),
+        }
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        self.db
+            .first()
+            .map(|entry| entry.map(|(key, _)| Self::ivec_to_str(key)))
+            .context("Failed to get the first key.")
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        self.db
+            .last()
+            .map(|entry| entry.map(|(key, _)| Self::ivec_to_str(key)))
+            .context("Failed to get the last key.")
+    }
+
+    fn keys_paginated(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let start = match after {
+            Some(key) => Bound::Excluded(Self::ivec_from_str(key)),
+            None => Bound::Unbounded,
+        };
+        let keys = self
+            .db
+            .range((start, Bound::Unbounded))
+            .keys()
+            .map(|key| key.map(Self::ivec_to_str).context("Failed to scan keys."));
+        key_page(keys, limit)
+    }
+
+    fn scan_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(key) => Bound::Excluded(Self::ivec_from_str(key)),
+            None => Bound::Unbounded,
+        };
+        self.db
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|entry| {
+                entry
+                    .map(|(key, value)| (Self::ivec_to_str(key), Self::ivec_to_str(value)))
+                    .context("Failed to scan entries.")
+            })
+            .collect()
+    }
+
+    fn transaction(&self, ops: Vec<WriteOp>) -> Result<()> {
+        let _lock = self.write_lock();
+        let result = self.db.transaction(|tx| {
+            for op in ops.iter() {
+                match op {
+                    WriteOp::Set { key, value } => {
+                        tx.insert(Self::ivec_from_str(key), Self::ivec_from_str(value))?;
+                    }
+                    WriteOp::Remove { key } => {
+                        if tx.remove(Self::ivec_from_str(key))?.is_none() {
+                            return abort(KvError::KeyNotFound(key.clone()));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(e)) => Err(e.into()),
+            Err(TransactionError::Storage(e)) => {
+                Err(anyhow::Error::from(e).context("Failed to apply the transaction."))
+            }
         }
     }
 
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        let _lock = self.write_lock();
+        let mut removed = 0;
+        for entry in self.db.scan_prefix(prefix).keys() {
+            if self.db.remove(entry?)?.is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn update(&self, key: &str, f: impl FnOnce(Option<String>) -> Option<String>) -> Result<()> {
+        let lock = self.write_lock();
+        let value = match call_update(f, self.get(key)?) {
+            Ok(value) => value,
+            Err(panic) => {
+                drop(lock);
+                panic::resume_unwind(panic)
+            }
+        };
+        let ikey = Self::ivec_from_str(key);
+        match value {
+            Some(value) => self.db.insert(ikey, Self::ivec_from_str(&value))?,
+            None => self.db.remove(ikey)?,
+        };
+        Ok(())
+    }
+
     fn flush(&self) -> Result<()> {
+        if self.async_flush {
+            self.flush_in_background();
+            return Ok(());
+        }
+        flush_if_dirty(&self.db, &self.dirty).context("Flush to disk.")
+    }
+
+    fn flush_async(&self) -> Result<()> {
+        self.flush_in_background();
+        Ok(())
+    }
+
+    /// Flush even if a background flush is still running, to return its result.
+    fn close(self) -> Result<()> {
         self.db.flush().map(|_| ()).context("Flush to disk.")
     }
 }
+
+impl SledAdapter {
+    /// Flush on a new thread, unless one is still at it, which then flushes again
+    /// for the writes it may have missed.
+    fn flush_in_background(&self) {
+        self.flush_requested.store(true, Ordering::SeqCst);
+        if self.flushing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let db = self.db.clone();
+        let (flushing, requested, dirty) = (
+            self.flushing.clone(),
+            self.flush_requested.clone(),
+            self.dirty.clone(),
+        );
+        thread::spawn(move || loop {
+            while requested.swap(false, Ordering::SeqCst) {
+                if let Err(e) = flush_if_dirty(&db, &dirty) {
+                    error!("Background flush failed: {:?}", e);
+                }
+            }
+            flushing.store(false, Ordering::SeqCst);
+            // A request made while giving up the flag is served here, unless a new
+            // thread took it.
+            if !requested.load(Ordering::SeqCst) || flushing.swap(true, Ordering::SeqCst) {
+                break;
+            }
+        });
+    }
+}
+
+/// Flush `db` if written since the last flush started, still dirty if it fails.
+fn flush_if_dirty(db: &Db, dirty: &AtomicBool) -> sled::Result<()> {
+    if !dirty.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+    db.flush()
+        .map(|_| ())
+        .inspect_err(|_| dirty.store(true, Ordering::SeqCst))
+}
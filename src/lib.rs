@@ -7,14 +7,30 @@ use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 
 pub use anyhow::Result;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvServer;
 pub use client::KvClient;
+pub use cluster::ClusterKvClient;
 pub use engine::KvsEngine;
-pub use server::KvServer;
+pub use metrics::Metrics;
+pub use replica::KvReplica;
+pub use server::{FlushPolicy, KvServer, ServerHandle};
 
+use engine::{Change, FileID, FileOffset};
+
+#[cfg(feature = "async")]
+mod async_server;
 mod client;
+mod cluster;
 pub mod engine;
+mod metrics;
+pub mod protocol;
+mod replica;
 mod server;
+mod stream;
 pub mod thread_pool;
+#[cfg(feature = "tls")]
+mod tls;
 
 /// Backend EngineType
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -63,28 +79,250 @@ impl From<EngineType> for String {
     }
 }
 
+/// Errors callers may want to tell apart, carried inside `anyhow::Error`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum KvError {
+    /// The key does not exist.
+    KeyNotFound(String),
+    /// The log file a change cursor points into was compacted away.
+    CursorCompacted(FileID),
+    /// The write would take the log files past `KvStoreConfig::max_disk_bytes`.
+    DiskQuotaExceeded,
+    /// The operation stopped early, its cancellation flag was set.
+    Cancelled,
+}
+
+impl Display for KvError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::KeyNotFound(key) => write!(f, "Key: {} not found.", key),
+            KvError::CursorCompacted(file_id) => {
+                write!(f, "Log file {} was compacted away.", file_id)
+            }
+            KvError::DiskQuotaExceeded => write!(f, "disk quota exceeded"),
+            KvError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// Output format of the command line tools.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    /// Plain text, for human.
+    Text,
+    /// One JSON object per invocation, for scripts.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => anyhow::bail!("Invalid output format: {}", s),
+        }
+    }
+}
+
 /// Instructions send by  KvClient/
 #[derive(Serialize, Deserialize, Debug, Clone)]
-enum Instruction {
+pub enum Instruction {
     /// Set a key-value pair.
-    Set { key: String, value: String },
+    Set {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+        /// Retries carrying the same one are answered without applying the set again.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        idempotency_key: Option<String>,
+    },
     /// Get key.
-    Get { key: String },
+    Get {
+        /// The key.
+        key: String,
+    },
     /// Remove a specific key.
-    Rm { key: String },
+    Rm {
+        /// The key.
+        key: String,
+    },
+    /// Authenticate the connection with the shared token.
+    Auth {
+        /// The shared token.
+        token: String,
+    },
+    /// Stream the writes from the log cursor `from` on, the whole store if `None`.
+    /// Answered by `ReplicationFrame`s.
+    Replicate {
+        /// The log cursor.
+        from: Option<(FileID, FileOffset)>,
+    },
+    /// Stream every key-value pair, answered by `ScanFrame`s.
+    ScanAll,
+    /// Apply the `Set` and `Rm` of `ops` all or none, answered once.
+    Transaction {
+        /// The writes.
+        ops: Vec<Instruction>,
+    },
+    /// The server counters in the Prometheus text format.
+    Metrics,
+    /// Values of several keys, answered at once by `Response::Values`.
+    MultiGet {
+        /// The keys.
+        keys: Vec<String>,
+    },
+    /// Remove several keys at once, answered by `Response::Removed`.
+    RemoveMany {
+        /// The keys.
+        keys: Vec<String>,
+    },
+    /// A page of keys in key order, answered by `Response::Keys`.
+    /// See `KvsEngine::keys_paginated`.
+    Keys {
+        /// The cursor of the previous page, `None` for the first one.
+        after: Option<String>,
+        /// Most keys in the page.
+        limit: usize,
+    },
+    /// Byte length of the value of a key, without sending the value.
+    ValueLen {
+        /// The key.
+        key: String,
+    },
+    /// Gzip the messages of at least `min_size` bytes both ways, from the answer on.
+    /// See `protocol::write_message_compressed`.
+    Compress {
+        /// Smallest message worth compressing, in bytes of JSON.
+        min_size: usize,
+    },
+    /// `instruction` tagged with an id the server logs it with,
+    /// answered by `Response::Traced` carrying the same id.
+    Traced {
+        /// Chosen by the client, e.g. a UUID.
+        request_id: String,
+        /// The instruction itself.
+        instruction: Box<Instruction>,
+    },
+}
+
+impl Instruction {
+    /// Whether it changes the store.
+    fn is_write(&self) -> bool {
+        match self {
+            Instruction::Traced { instruction, .. } => instruction.is_write(),
+            _ => matches!(
+                self,
+                Instruction::Set { .. }
+                    | Instruction::Rm { .. }
+                    | Instruction::RemoveMany { .. }
+                    | Instruction::Transaction { .. }
+            ),
+        }
+    }
+
+    /// Whether sending it again can't apply it twice: reads, and sets with an idempotency key.
+    fn is_retryable(&self) -> bool {
+        match self {
+            Instruction::Traced { instruction, .. } => instruction.is_retryable(),
+            Instruction::Set {
+                idempotency_key, ..
+            } => idempotency_key.is_some(),
+            ins => !ins.is_write(),
+        }
+    }
+
+    /// The request id of a `Traced` instruction and the instruction it carries,
+    /// no id for the others.
+    fn untraced(self) -> (Option<String>, Instruction) {
+        match self {
+            Instruction::Traced {
+                request_id,
+                instruction,
+            } => (Some(request_id), *instruction),
+            ins => (None, ins),
+        }
+    }
 }
 
+/// What the server streams to a replica after `Instruction::Replicate`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-enum Response {
+pub enum ReplicationFrame {
+    /// The cursor is gone, drop everything, the whole store follows.
+    Resync,
+    /// Next write to apply.
+    Change(Change),
+    /// Nothing new, sent to tell the connection alive.
+    Heartbeat,
+    /// The primary failed to read the next write, the stream ends.
+    Error(String),
+}
+
+/// What the server streams after `Instruction::ScanAll`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ScanFrame {
+    /// Next pair, in key order.
+    Pair(String, String),
+    /// No pair left.
+    End,
+    /// The scan failed, shaped like `Response::Error` so rejections read the same.
+    Error(String),
+}
+
+/// Answer of the server to an instruction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Response {
+    /// Done, with the value for `Get`.
     Ok(String),
+    /// Rejected, with the reason.
     Error(String),
+    /// The key of a `Get` or `Rm` does not exist.
+    NotFound(String),
+    /// Values of the keys of a `MultiGet` in the same order, `None` for the missing ones.
+    Values(Vec<Option<String>>),
+    /// Whether each key of a `RemoveMany` existed and was removed, in the same order.
+    Removed(Vec<bool>),
+    /// A page of `Instruction::Keys`.
+    Keys {
+        /// The keys, in key order.
+        keys: Vec<String>,
+        /// The cursor of the next page, `None` once no key is left.
+        next: Option<String>,
+    },
+    /// Answer to an `Instruction::Traced`, with its request id.
+    Traced {
+        /// The id the client sent.
+        request_id: String,
+        /// The answer itself.
+        response: Box<Response>,
+    },
+}
+
+impl Response {
+    /// Tagged with `request_id` if the instruction was.
+    fn traced(self, request_id: Option<String>) -> Self {
+        match request_id {
+            Some(request_id) => Response::Traced {
+                request_id,
+                response: Box::new(self),
+            },
+            None => self,
+        }
+    }
 }
 
 impl From<Result<String>> for Response {
     fn from(res: Result<String>) -> Self {
         match res {
             Ok(x) => Response::Ok(x),
-            Err(e) => Response::Error(e.to_string()),
+            Err(e) => match e.downcast_ref::<KvError>() {
+                Some(KvError::KeyNotFound(key)) => Response::NotFound(key.clone()),
+                _ => Response::Error(e.to_string()),
+            },
         }
     }
 }
@@ -94,6 +332,15 @@ impl From<Response> for Result<String, String> {
         match res {
             Response::Ok(s) => Ok(s),
             Response::Error(s) => Err(s),
+            Response::NotFound(key) => Err(KvError::KeyNotFound(key).to_string()),
+            Response::Values(values) => serde_json::to_string(&values).map_err(|e| e.to_string()),
+            Response::Removed(removed) => {
+                serde_json::to_string(&removed).map_err(|e| e.to_string())
+            }
+            Response::Keys { keys, next } => {
+                serde_json::to_string(&(keys, next)).map_err(|e| e.to_string())
+            }
+            Response::Traced { response, .. } => (*response).into(),
         }
     }
 }
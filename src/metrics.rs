@@ -0,0 +1,150 @@
+//! Counters of a `KvServer`, exported in the Prometheus text format.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use crate::Instruction;
+
+/// Label of each instruction type, indexed by `op_index`.
+const OPS: [&str; 13] = [
+    "get",
+    "set",
+    "rm",
+    "auth",
+    "replicate",
+    "scan_all",
+    "transaction",
+    "metrics",
+    "compress",
+    "value_len",
+    "multi_get",
+    "remove_many",
+    "keys",
+];
+
+/// Label of the type of `ins`.
+pub(crate) fn op_name(ins: &Instruction) -> &'static str {
+    OPS[op_index(ins)]
+}
+
+fn op_index(ins: &Instruction) -> usize {
+    match ins {
+        Instruction::Get { .. } => 0,
+        Instruction::Set { .. } => 1,
+        Instruction::Rm { .. } => 2,
+        Instruction::Auth { .. } => 3,
+        Instruction::Replicate { .. } => 4,
+        Instruction::ScanAll => 5,
+        Instruction::Transaction { .. } => 6,
+        Instruction::Metrics => 7,
+        Instruction::Compress { .. } => 8,
+        Instruction::ValueLen { .. } => 9,
+        Instruction::MultiGet { .. } => 10,
+        Instruction::RemoveMany { .. } => 11,
+        Instruction::Keys { .. } => 12,
+        Instruction::Traced { instruction, .. } => op_index(instruction),
+    }
+}
+
+/// Registry of the server counters, each update is a single atomic add.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    ops: [AtomicU64; OPS.len()],
+    errors: AtomicU64,
+    read_bytes: AtomicU64,
+    written_bytes: AtomicU64,
+    active_connections: AtomicI64,
+    compactions: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a compaction of the engine, the server can't see them itself.
+    pub fn record_compaction(&self) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_op(&self, ins: &Instruction) {
+        self.ops[op_index(ins)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_read(&self, bytes: usize) {
+        self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_written(&self, bytes: usize) {
+        self.written_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Every counter in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "kvs_operations_total",
+            "counter",
+            "Instructions received, by type.",
+        );
+        for (op, count) in OPS.iter().zip(&self.ops) {
+            let _ = writeln!(
+                out,
+                "kvs_operations_total{{op=\"{}\"}} {}",
+                op,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let single: [(&str, &str, &str, i64); 5] = [
+            (
+                "kvs_errors_total",
+                "counter",
+                "Instructions answered with an error.",
+                self.errors.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "kvs_read_bytes_total",
+                "counter",
+                "Bytes of keys and values read.",
+                self.read_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "kvs_written_bytes_total",
+                "counter",
+                "Bytes of keys and values written.",
+                self.written_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "kvs_active_connections",
+                "gauge",
+                "Connections open.",
+                self.active_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "kvs_compactions_total",
+                "counter",
+                "Compactions of the engine.",
+                self.compactions.load(Ordering::Relaxed) as i64,
+            ),
+        ];
+        for (name, kind, help, value) in single {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
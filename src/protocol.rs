@@ -0,0 +1,207 @@
+//! Framing of the wire protocol, each message is one line of JSON.
+//!
+//! A client writes an `Instruction` and reads back a `Response`, except for
+//! `Instruction::ScanAll` and `Instruction::Replicate` answered by a stream of
+//! `ScanFrame`s and `ReplicationFrame`s.
+//!
+//! After `Instruction::Compress` large messages are gzipped instead, framed by a
+//! `COMPRESSED_FRAME` byte and their compressed length. `read_message` reads both,
+//! refusing a frame inflating past `MAX_MESSAGE_LEN`.
+//!
+//! A client may lead the connection with a byte choosing its `Framing`, the lines
+//! above with `LINES_PREFIX` or length-prefixed messages with `LENGTH_PREFIX`.
+//! Clients sending neither speak lines.
+//!
+//! ```
+//! use kvs::protocol::{read_message, write_message};
+//! use kvs::{Instruction, Response};
+//!
+//! let mut request = Vec::new();
+//! let ins = Instruction::Set {
+//!     key: "key1".to_owned(),
+//!     value: "value1".to_owned(),
+//!     idempotency_key: None,
+//! };
+//! write_message(&mut request, &ins)?;
+//! assert_eq!(request, b"{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n");
+//!
+//! let reply = b"{\"Error\":\"Authentication required.\"}\n";
+//! let response: Option<Response> = read_message(&mut &reply[..])?;
+//! assert!(matches!(response, Some(Response::Error(msg)) if msg == "Authentication required."));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Leads a compressed message, JSON never starts with it.
+pub const COMPRESSED_FRAME: u8 = 0x1f;
+
+/// Longest JSON a compressed frame may inflate to, a longer one is refused before
+/// it fills the memory.
+pub const MAX_MESSAGE_LEN: u64 = 64 << 20;
+
+/// Leads a connection speaking lines.
+pub const LINES_PREFIX: u8 = 0x00;
+
+/// Leads a connection speaking length-prefixed messages.
+pub const LENGTH_PREFIX: u8 = 0x01;
+
+/// How the messages of a connection are delimited, chosen by its first byte.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// One line of JSON per message, or a compressed message.
+    #[default]
+    Lines,
+    /// The JSON of each message led by its length as a big-endian `u32`.
+    LengthPrefixed,
+}
+
+impl Framing {
+    /// Byte a client sends first to choose this framing.
+    pub fn prefix(self) -> u8 {
+        match self {
+            Framing::Lines => LINES_PREFIX,
+            Framing::LengthPrefixed => LENGTH_PREFIX,
+        }
+    }
+
+    /// Consume the prefix leading a connection, `None` once the client closed it.
+    /// Without a prefix the connection speaks lines and nothing is consumed.
+    pub fn detect(reader: &mut impl BufRead) -> Result<Option<Self>> {
+        let framing = match reader.fill_buf()?.first() {
+            None => return Ok(None),
+            Some(&LINES_PREFIX) => Framing::Lines,
+            Some(&LENGTH_PREFIX) => Framing::LengthPrefixed,
+            Some(_) => return Ok(Some(Framing::Lines)),
+        };
+        reader.consume(1);
+        Ok(Some(framing))
+    }
+
+    /// Write `message` in this framing, flushing is left to the caller.
+    pub fn write(self, writer: &mut (impl Write + ?Sized), message: &impl Serialize) -> Result<()> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf, message)?;
+        Ok(writer.write_all(&buf)?)
+    }
+
+    /// Replace the content of `buf` by `message` in this framing, so a connection can
+    /// reuse one buffer for all its messages rather than allocate one each.
+    pub fn encode(self, buf: &mut Vec<u8>, message: &impl Serialize) -> Result<()> {
+        buf.clear();
+        match self {
+            Framing::Lines => {
+                serde_json::to_writer(&mut *buf, message)?;
+                buf.push(b'\n');
+            }
+            Framing::LengthPrefixed => {
+                buf.extend_from_slice(&[0; 4]);
+                serde_json::to_writer(&mut *buf, message)?;
+                let len = (buf.len() - 4) as u32;
+                buf[..4].copy_from_slice(&len.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the next message in this framing, `None` once the other side closed the stream.
+    pub fn read<T: DeserializeOwned>(self, reader: &mut impl BufRead) -> Result<Option<T>> {
+        match self {
+            Framing::Lines => read_message(reader),
+            Framing::LengthPrefixed => {
+                if reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let mut len = [0; 4];
+                reader.read_exact(&mut len)?;
+                let len = u32::from_be_bytes(len) as u64;
+                let mut json = Vec::new();
+                reader.take(len).read_to_end(&mut json)?;
+                if json.len() as u64 != len {
+                    bail!("Message cut after {} of {} bytes.", json.len(), len);
+                }
+                serde_json::from_slice(&json)
+                    .map(Some)
+                    .context("Error when parsing a length-prefixed message from json.")
+            }
+        }
+    }
+}
+
+/// Write `message` as one line, flushing is left to the caller.
+pub fn write_message(writer: &mut (impl Write + ?Sized), message: &impl Serialize) -> Result<()> {
+    Framing::Lines.write(writer, message)
+}
+
+/// Write `message` gzipped if its JSON takes at least `min_size` bytes, as a line otherwise.
+pub fn write_message_compressed(
+    writer: &mut (impl Write + ?Sized),
+    message: &impl Serialize,
+    min_size: usize,
+) -> Result<()> {
+    let mut json = serde_json::to_vec(message)?;
+    if json.len() < min_size {
+        json.push(b'\n');
+        return Ok(writer.write_all(&json)?);
+    }
+    // Framed in place so the message leaves in a single write.
+    let mut encoder = GzEncoder::new(vec![COMPRESSED_FRAME, 0, 0, 0, 0], Compression::fast());
+    encoder.write_all(&json)?;
+    let mut frame = encoder.finish()?;
+    let len = (frame.len() - 5) as u32;
+    frame[1..5].copy_from_slice(&len.to_be_bytes());
+    Ok(writer.write_all(&frame)?)
+}
+
+/// Read the next message, `None` once the other side closed the stream.
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    match reader.fill_buf()?.first() {
+        None => return Ok(None),
+        Some(&COMPRESSED_FRAME) => return read_compressed(reader).map(Some),
+        Some(_) => (),
+    }
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    serde_json::from_str(line.trim())
+        .map(Some)
+        .with_context(|| format!("Error when parsing from json. {}", line))
+}
+
+fn read_compressed<T: DeserializeOwned>(reader: &mut impl BufRead) -> Result<T> {
+    reader.consume(1);
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as u64;
+    if len > MAX_MESSAGE_LEN {
+        bail!("Compressed message of {} bytes, longer than allowed.", len);
+    }
+    let mut compressed = Vec::new();
+    reader.take(len).read_to_end(&mut compressed)?;
+    if compressed.len() as u64 != len {
+        bail!(
+            "Compressed message cut after {} of {} bytes.",
+            compressed.len(),
+            len
+        );
+    }
+    let mut json = Vec::new();
+    GzDecoder::new(&compressed[..])
+        .take(MAX_MESSAGE_LEN + 1)
+        .read_to_end(&mut json)
+        .context("Error when decompressing a message.")?;
+    if json.len() as u64 > MAX_MESSAGE_LEN {
+        bail!(
+            "Compressed message inflating past {} bytes.",
+            MAX_MESSAGE_LEN
+        );
+    }
+    serde_json::from_slice(&json).context("Error when parsing a compressed message from json.")
+}
@@ -0,0 +1,122 @@
+use std::fmt::Debug;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::*;
+
+use crate::engine::{Change, FileID, FileOffset};
+use crate::protocol::{read_message, write_message};
+use crate::stream::resolve;
+use crate::{Instruction, KvError, KvsEngine, ReplicationFrame};
+
+/// Wait before connecting again to the primary.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The primary sends heartbeats far more often, silence this long means it is gone.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Position in the primary's log up to which changes were applied.
+type Cursor = Arc<Mutex<Option<(FileID, FileOffset)>>>;
+
+/// Follows a primary `KvServer`, applying its writes onto a local store on a background thread.
+/// Stops when dropped.
+pub struct KvReplica {
+    stop: Arc<AtomicBool>,
+    cursor: Cursor,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl KvReplica {
+    /// Apply the writes of the primary listening on `primary_addr` onto `store`, reconnecting
+    /// and resuming from the last applied change when the connection drops.
+    /// The store is cleared first, unless resuming is possible.
+    pub fn follow<E: KvsEngine>(
+        primary_addr: impl ToSocketAddrs + Debug,
+        store: E,
+    ) -> Result<Self> {
+        let addrs = resolve(&primary_addr)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let cursor = Cursor::default();
+        let handle = {
+            let (stop, cursor) = (stop.clone(), cursor.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    if let Err(e) = replicate(&addrs, &store, &stop, &cursor) {
+                        warn!("Replication from {:?} interrupted: {}", addrs, e);
+                        thread::sleep(RECONNECT_INTERVAL);
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            stop,
+            cursor,
+            handle: Some(handle),
+        })
+    }
+
+    /// Position in the primary's log up to which changes were applied, `None` before any.
+    pub fn position(&self) -> Option<(FileID, FileOffset)> {
+        *self.cursor.lock().unwrap()
+    }
+}
+
+impl Drop for KvReplica {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Apply the frames of one connection until `stop` is set or the connection fails.
+fn replicate<E: KvsEngine>(
+    addrs: &[SocketAddr],
+    store: &E,
+    stop: &AtomicBool,
+    cursor: &Cursor,
+) -> Result<()> {
+    let stream = TcpStream::connect(addrs)?;
+    stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let from = *cursor.lock().unwrap();
+    write_message(reader.get_mut(), &Instruction::Replicate { from })?;
+    reader.get_mut().flush()?;
+    while !stop.load(Ordering::SeqCst) {
+        let frame = match read_message(&mut reader)? {
+            Some(frame) => frame,
+            None => bail!("Connection closed by the primary."),
+        };
+        match frame {
+            ReplicationFrame::Resync => {
+                let removed = store.remove_prefix("")?;
+                info!("Resync from the primary, {} keys dropped.", removed);
+                *cursor.lock().unwrap() = None;
+            }
+            ReplicationFrame::Change(change) => {
+                let position = (change.file_id, change.next_offset);
+                apply(store, change)?;
+                *cursor.lock().unwrap() = Some(position);
+            }
+            ReplicationFrame::Heartbeat => (),
+            ReplicationFrame::Error(e) => bail!("The primary failed to read its log: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn apply<E: KvsEngine>(store: &E, change: Change) -> Result<()> {
+    match change.value {
+        Some(value) => store.set(&change.key, &value),
+        None => match store.remove(&change.key) {
+            Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyNotFound(_))) => Ok(()),
+            removed => removed,
+        },
+    }
+}
@@ -1,70 +1,906 @@
-use std::io::{BufRead, BufReader, LineWriter, Write};
-use std::net::{TcpListener, ToSocketAddrs};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use log::*;
-use serde_json;
+use serde::Serialize;
+use socket2::SockRef;
 
+use crate::engine::{FileID, FileOffset, WriteOp};
+use crate::metrics::op_name;
+use crate::protocol::{write_message, write_message_compressed, Framing, COMPRESSED_FRAME};
+use crate::stream::{resolve, Stream};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Response};
+use crate::{KvError, KvsEngine, Metrics, ReplicationFrame, Response, ScanFrame};
 
 use super::Instruction;
 
+/// How often the dispatching loop checks for shutdown while no request arrives.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Pairs read from the engine at once while streaming a scan.
+pub(crate) const SCAN_PAGE_SIZE: usize = 256;
+/// Idempotency keys remembered, retries coming after this many others apply again.
+const IDEMPOTENCY_KEYS: usize = 1024;
+/// Capacity of the answer buffer of a connection kept after a larger answer.
+const REPLY_BUFFER_KEPT: usize = 64 * 1024;
+
+/// A request ready to be processed by the pool.
+type Task = Box<dyn FnOnce() + Send>;
+
 /// KvServer, accept instructions from kvclient and process by kv engine.
 pub struct KvServer<T: KvsEngine, K: ThreadPool> {
     pub(crate) server: TcpListener,
     pub(crate) engine: T,
     pool: K,
+    shutdown: Arc<AtomicBool>,
+    in_flight: Arc<RequestTracker>,
+    options: ServerOptions,
+    metrics: Arc<Metrics>,
+}
+
+/// When the server flushes the engine after writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlushPolicy {
+    /// After every write, the safest and slowest.
+    #[default]
+    EveryWrite,
+    /// After every `n` writes, counted over all connections.
+    EveryN(usize),
+    /// When a connection which wrote is closed.
+    OnDisconnect,
+}
+
+/// Tunables applied to every connection.
+#[derive(Clone, Default)]
+struct ServerOptions {
+    auth_token: Option<String>,
+    idle_timeout: Option<Duration>,
+    op_timeout: Option<Duration>,
+    flush_policy: FlushPolicy,
+    read_only: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl ServerOptions {
+    fn wrap_stream(&self, stream: TcpStream) -> Result<Box<dyn Stream>> {
+        stream.set_read_timeout(self.idle_timeout)?;
+        SockRef::from(&stream).set_keepalive(true)?;
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let connection = rustls::ServerConnection::new(config.clone())?;
+            return Ok(Box::new(rustls::StreamOwned::new(connection, stream)));
+        }
+        Ok(Box::new(stream))
+    }
+}
+
+/// What a connection handler needs from the server.
+#[derive(Clone)]
+struct ConnectionContext {
+    shutdown: Arc<AtomicBool>,
+    in_flight: Arc<RequestTracker>,
+    options: Arc<ServerOptions>,
+    tasks: Sender<Task>,
+    /// Writes since the server started, for `FlushPolicy::EveryN`.
+    writes: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+    /// Shared by the connections, retries usually come on a new one.
+    applied: Arc<Mutex<AppliedRequests>>,
+}
+
+impl ConnectionContext {
+    /// Whether the write just processed should be followed by a flush.
+    fn flush_after_write(&self) -> bool {
+        match self.options.flush_policy {
+            FlushPolicy::EveryWrite => true,
+            FlushPolicy::EveryN(n) => {
+                // true on the n-th, 2n-th, ... write.
+                let n = n.max(1);
+                self.writes.fetch_add(1, Ordering::SeqCst) % n == n - 1
+            }
+            FlushPolicy::OnDisconnect => false,
+        }
+    }
+}
+
+/// Answers of the latest requests carrying an idempotency key, oldest dropped first.
+#[derive(Default)]
+pub(crate) struct AppliedRequests {
+    order: VecDeque<String>,
+    /// `None` while the request is being applied.
+    answers: HashMap<String, Option<Response>>,
+}
+
+impl AppliedRequests {
+    /// Reserve `idempotency_key` for a request about to be applied, unless an earlier one
+    /// holds it: `Err` with its answer then, `None` while it is still being applied.
+    fn reserve(&mut self, idempotency_key: &str) -> std::result::Result<(), Option<Response>> {
+        if let Some(answer) = self.answers.get(idempotency_key) {
+            return Err(answer.clone());
+        }
+        self.insert(idempotency_key.to_owned(), None);
+        Ok(())
+    }
+
+    fn insert(&mut self, idempotency_key: String, answer: Option<Response>) {
+        if self
+            .answers
+            .insert(idempotency_key.clone(), answer)
+            .is_none()
+        {
+            self.order.push_back(idempotency_key);
+        }
+        while self.order.len() > IDEMPOTENCY_KEYS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.answers.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget a reservation whose request failed, so a retry applies it.
+    fn release(&mut self, idempotency_key: &str) {
+        if self.answers.remove(idempotency_key).is_some() {
+            self.order.retain(|key| key != idempotency_key);
+        }
+    }
+}
+
+/// An idempotency key reserved by the request applying it. The answer is recorded by the
+/// task, also when the client stopped waiting for it, the key released if it failed.
+pub(crate) struct Reservation {
+    applied: Arc<Mutex<AppliedRequests>>,
+    idempotency_key: Option<String>,
+}
+
+impl Reservation {
+    /// Reserve the idempotency key of `ins`, if it has one, or the answer to give instead:
+    /// the one recorded for it, or an error while an earlier request with it is applied.
+    pub(crate) fn take(
+        applied: &Arc<Mutex<AppliedRequests>>,
+        ins: &Instruction,
+    ) -> std::result::Result<Self, Response> {
+        let idempotency_key = match ins {
+            Instruction::Set {
+                idempotency_key: Some(idempotency_key),
+                ..
+            } => Some(idempotency_key.clone()),
+            _ => None,
+        };
+        if let Some(idempotency_key) = &idempotency_key {
+            match applied.lock().unwrap().reserve(idempotency_key) {
+                Ok(()) => (),
+                Err(Some(resp)) => {
+                    debug!("Request {} already applied.", idempotency_key);
+                    return Err(resp);
+                }
+                Err(None) => {
+                    let in_progress = format!("Request {} is in progress.", idempotency_key);
+                    return Err(Response::Error(in_progress));
+                }
+            }
+        }
+        Ok(Self {
+            applied: applied.clone(),
+            idempotency_key,
+        })
+    }
+
+    pub(crate) fn record(mut self, resp: &Response) {
+        if let (Some(idempotency_key), Response::Ok(_)) = (self.idempotency_key.take(), resp) {
+            self.applied
+                .lock()
+                .unwrap()
+                .insert(idempotency_key, Some(resp.clone()));
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(idempotency_key) = self.idempotency_key.take() {
+            if let Ok(mut applied) = self.applied.lock() {
+                applied.release(&idempotency_key);
+            }
+        }
+    }
+}
+
+/// What the pool answers a request with.
+enum Answer {
+    Response(Response),
+    /// The answer to a `Get`, encoded into the reply buffer handed to the pool.
+    Encoded(Vec<u8>),
+}
+
+/// `Response::Ok` borrowing its value, encoded the same.
+#[derive(Serialize)]
+enum ValueResponse<'a> {
+    Ok(&'a str),
+}
+
+/// Handle used to stop a running `KvServer` from another thread (e.g. a signal handler).
+#[derive(Clone, Debug)]
+pub struct ServerHandle {
+    shutdown: Arc<AtomicBool>,
+    address: SocketAddr,
+}
+
+impl ServerHandle {
+    /// Ask the server to stop accepting connections, the `run` loop returns once the
+    /// in-flight requests are drained and the engine is flushed.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake up the blocking `accept`.
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+/// The thread reading a connection, with the socket to close to stop it.
+struct ConnectionReader {
+    socket: Arc<TcpStream>,
+    thread: JoinHandle<()>,
+}
+
+/// Count requests being processed, so shutdown can wait for them.
+#[derive(Default)]
+struct RequestTracker {
+    count: Mutex<usize>,
+    drained: Condvar,
+}
+
+/// Marks a request in flight until dropped.
+struct RequestGuard(Arc<RequestTracker>);
+
+impl RequestTracker {
+    fn enter(self: &Arc<Self>) -> RequestGuard {
+        *self.count.lock().unwrap() += 1;
+        RequestGuard(self.clone())
+    }
+
+    fn leave(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.drained.notify_all();
+        }
+    }
+
+    fn wait_drained(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.drained.wait(count).unwrap();
+        }
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.leave();
+    }
 }
 
 impl<T: KvsEngine, K: ThreadPool> KvServer<T, K> {
     /// Construct a new instance through ServerConfig.
-    pub fn new(engine: T, pool: K, address: impl ToSocketAddrs) -> Result<Self> {
+    pub fn new(engine: T, pool: K, address: impl ToSocketAddrs + Debug) -> Result<Self> {
+        let addrs = resolve(&address)?;
         Ok(KvServer {
-            server: TcpListener::bind(address)?,
+            server: TcpListener::bind(&addrs[..])
+                .with_context(|| format!("Failed to bind {:?}.", addrs))?,
             engine,
             pool,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(RequestTracker::default()),
+            options: ServerOptions::default(),
+            metrics: Default::default(),
         })
     }
 
-    fn process_instruction(engine: &mut T, inst: &Instruction) -> Result<Response> {
-        Ok(Response::from({
-            debug!("command: {:?}", inst);
-            let ret = match inst {
-                Instruction::Get { key } => engine
-                    .get(&key)
-                    .map(|x| x.unwrap_or(format!("Key: {} not found", key))),
-                Instruction::Set { key, value } => engine.set(&key, &value).map(|_| "".to_owned()),
-                Instruction::Rm { key } => engine.remove(&key).map(|_| "".to_owned()),
-            };
-            engine.flush().unwrap();
-            ret
-        }))
+    /// Require clients to send an `Auth` instruction carrying `token` before anything else.
+    pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+        self.options.auth_token = Some(token.into());
+        self
+    }
+
+    /// Close connections which send no instruction for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Answer with an error requests the engine takes longer than `timeout` to process.
+    /// The stuck operation keeps its worker and may still complete, a timed out write
+    /// can be applied after all.
+    pub fn with_op_timeout(mut self, timeout: Duration) -> Self {
+        self.options.op_timeout = Some(timeout);
+        self
+    }
+
+    /// Reject every write with an error, e.g. to serve a snapshot which must stay as it is.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Choose when the engine is flushed, after every write by default.
+    pub fn with_flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.options.flush_policy = policy;
+        self
+    }
+
+    /// Record into `metrics`, e.g. to count compactions from the engine's hook.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Serve connections over TLS, with PEM encoded certificate chain and private key.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        self.options.tls = Some(crate::tls::server_config(cert.as_ref(), key.as_ref())?);
+        Ok(self)
     }
 
-    /// Start  receiving instructions from client continuesly..
-    pub fn run(self) -> ! {
+    /// Get a handle which is able to stop the server later.
+    pub fn handle(&self) -> Result<ServerHandle> {
+        let mut address = self.server.local_addr()?;
+        if address.ip().is_unspecified() {
+            address.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        Ok(ServerHandle {
+            shutdown: self.shutdown.clone(),
+            address,
+        })
+    }
+
+    /// Start  receiving instructions from client continuesly, until shutdown through a `ServerHandle`.
+    /// Each connection is read on its own thread, the pool only runs the requests.
+    pub fn run(self) -> Result<()> {
+        let (tasks, queued) = mpsc::channel::<Task>();
+        let context = ConnectionContext {
+            shutdown: self.shutdown.clone(),
+            in_flight: self.in_flight.clone(),
+            options: Arc::new(self.options.clone()),
+            tasks,
+            writes: Default::default(),
+            metrics: self.metrics.clone(),
+            applied: Default::default(),
+        };
+        let listener = self.server.try_clone()?;
+        let engine = self.engine.clone();
+        let acceptor = thread::spawn(move || accept_connections(listener, engine, context));
         loop {
-            let (stream, client_addr) = self.server.accept().unwrap();
-            info!("Accept connection from client: {:?}", client_addr);
-            {
-                let mut engine = self.engine.clone();
-                self.pool.spawn(move || {
-                    let buf_reader = BufReader::new(&stream);
-                    let mut line_writer = LineWriter::new(&stream);
-                    for line in buf_reader.lines() {
-                        let line = line.unwrap();
-                        debug!("[client->server] {}", line);
-                        let ins = serde_json::from_str::<Instruction>(&line).unwrap();
-                        let resp = Self::process_instruction(&mut engine, &ins).unwrap();
-                        debug!("[server->client] {:?}", resp);
-                        let serialized = serde_json::to_string(&resp)
-                            .unwrap_or("Failed to serialize response.".to_string());
-                        writeln!(&mut line_writer, "{}", serialized).unwrap();
-                    }
-                });
+            match queued.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                Ok(task) => self.pool.spawn(task),
+                Err(RecvTimeoutError::Timeout) if !self.shutdown.load(Ordering::SeqCst) => (),
+                Err(_) => break,
+            }
+        }
+        // Requests still queued are dropped, their connections get closed.
+        drop(queued);
+        let readers = acceptor.join().unwrap_or_default();
+        info!("Stop accepting connections, draining in-flight requests.");
+        self.in_flight.wait_drained();
+        // Close the connections still open so their readers stop waiting for requests.
+        for reader in readers {
+            let _ = reader.socket.shutdown(Shutdown::Both);
+            let _ = reader.thread.join();
+        }
+        self.engine.close()
+    }
+}
+
+/// Accept connections until shutdown, returning the readers of the ones not closed yet.
+fn accept_connections<T: KvsEngine>(
+    listener: TcpListener,
+    engine: T,
+    context: ConnectionContext,
+) -> Vec<ConnectionReader> {
+    let mut readers: Vec<ConnectionReader> = Vec::new();
+    for stream in listener.incoming() {
+        if context.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let client_addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Failed to get the client address: {}", e);
+                continue;
+            }
+        };
+        info!("Accept connection from client: {:?}", client_addr);
+        let socket = match stream.try_clone() {
+            Ok(socket) => Arc::new(socket),
+            Err(e) => {
+                error!("Failed to set up connection with {:?}: {}", client_addr, e);
+                continue;
             }
+        };
+        let stream = match context.options.wrap_stream(stream) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to set up connection with {:?}: {}", client_addr, e);
+                continue;
+            }
+        };
+        let engine = engine.clone();
+        let context = context.clone();
+        let closed = socket.clone();
+        let thread = thread::spawn(move || {
+            serve_connection(engine, stream, context);
+            // The clone kept for shutdown would hold the connection open otherwise.
+            let _ = closed.shutdown(Shutdown::Both);
             info!("Client: {:?} disconnected", client_addr);
+        });
+        readers.retain(|reader| !reader.thread.is_finished());
+        readers.push(ConnectionReader { socket, thread });
+    }
+    readers
+}
+
+pub(crate) fn process_instruction<T: KvsEngine>(
+    engine: &mut T,
+    inst: &Instruction,
+    metrics: &Metrics,
+) -> Result<Response> {
+    Ok(Response::from({
+        debug!("command: {:?}", inst);
+        let ret = match inst {
+            Instruction::Get { key } => match engine.get(&key) {
+                Ok(Some(value)) => {
+                    metrics.record_read(key.len() + value.len());
+                    Ok(value)
+                }
+                // Not an error, unlike removing a missing key.
+                Ok(None) => return Ok(Response::NotFound(key.clone())),
+                Err(e) => Err(e),
+            },
+            Instruction::MultiGet { keys } => {
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                match engine.get_many(&keys) {
+                    Ok(values) => {
+                        let bytes = keys
+                            .iter()
+                            .zip(&values)
+                            .map(|(key, value)| key.len() + value.as_ref().map_or(0, String::len));
+                        metrics.record_read(bytes.sum());
+                        return Ok(Response::Values(values));
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Instruction::ValueLen { key } => match engine.value_len(&key) {
+                Ok(Some(len)) => Ok(len.to_string()),
+                Ok(None) => return Ok(Response::NotFound(key.clone())),
+                Err(e) => Err(e),
+            },
+            Instruction::Set { key, value, .. } => engine.set(&key, &value).map(|_| {
+                metrics.record_written(key.len() + value.len());
+                "".to_owned()
+            }),
+            Instruction::Rm { key } => engine.remove(&key).map(|_| "".to_owned()),
+            Instruction::RemoveMany { keys } => {
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                match engine.remove_batch(&keys) {
+                    Ok(removed) => return Ok(Response::Removed(removed)),
+                    Err(e) => Err(e),
+                }
+            }
+            Instruction::Keys { after, limit } => {
+                match engine.keys_paginated(after.as_deref(), *limit) {
+                    Ok((keys, next)) => return Ok(Response::Keys { keys, next }),
+                    Err(e) => Err(e),
+                }
+            }
+            Instruction::Auth { .. } => Ok("".to_owned()),
+            Instruction::Replicate { .. } => Err(anyhow!("Replication is served by connections.")),
+            Instruction::ScanAll | Instruction::Metrics | Instruction::Compress { .. } => {
+                Err(anyhow!("Served by connections, not the pool."))
+            }
+            Instruction::Traced { instruction, .. } => {
+                return process_instruction(engine, instruction, metrics)
+            }
+            Instruction::Transaction { ops } => ops
+                .iter()
+                .map(write_op)
+                .collect::<Result<Vec<_>>>()
+                .and_then(|ops| engine.transaction(ops))
+                .map(|_| "".to_owned()),
+        };
+        if ret.is_err() {
+            metrics.record_error();
+        }
+        ret
+    }))
+}
+
+/// The engine write an instruction of a transaction stands for.
+fn write_op(ins: &Instruction) -> Result<WriteOp> {
+    match ins {
+        Instruction::Set { key, value, .. } => Ok(WriteOp::Set {
+            key: key.clone(),
+            value: value.clone(),
+        }),
+        Instruction::Rm { key } => Ok(WriteOp::Remove { key: key.clone() }),
+        _ => Err(anyhow!("Only set and rm are allowed in a transaction.")),
+    }
+}
+
+/// Encode the answer to a `Get` of `key` into `buf`, the value found going from the engine
+/// to the encoder without a copy where the engine keeps it in memory.
+fn encode_get<T: KvsEngine>(
+    engine: &T,
+    key: &str,
+    framing: Framing,
+    buf: &mut Vec<u8>,
+    metrics: &Metrics,
+) -> Result<()> {
+    let found = engine.get_with(key, |value| {
+        metrics.record_read(key.len() + value.len());
+        framing.encode(buf, &ValueResponse::Ok(value))
+    });
+    match found {
+        Ok(Some(encoded)) => encoded,
+        Ok(None) => framing.encode(buf, &Response::NotFound(key.to_owned())),
+        Err(e) => {
+            metrics.record_error();
+            framing.encode(buf, &Response::from(Err::<String, _>(e)))
+        }
+    }
+}
+
+/// Hand the instruction to the pool and wait for its response,
+/// `None` if it was dropped because the server is shutting down.
+/// A `Get` given a reply buffer is answered with it, encoded in the given framing.
+fn dispatch<T: KvsEngine>(
+    engine: &T,
+    ins: Instruction,
+    context: &ConnectionContext,
+    encoding: Option<(Framing, Vec<u8>)>,
+) -> Option<Answer> {
+    let reservation = match Reservation::take(&context.applied, &ins) {
+        Ok(reservation) => reservation,
+        Err(resp) => return Some(Answer::Response(resp)),
+    };
+    let (reply, replied) = mpsc::channel();
+    let mut engine = engine.clone();
+    let task_context = context.clone();
+    let op = op_name(&ins);
+    context
+        .tasks
+        .send(Box::new(move || {
+            let context = task_context;
+            if let (Instruction::Get { key }, Some((framing, mut buf))) = (&ins, encoding) {
+                let answer = match encode_get(&engine, key, framing, &mut buf, &context.metrics) {
+                    Ok(()) => Answer::Encoded(buf),
+                    Err(e) => Answer::Response(Response::Error(e.to_string())),
+                };
+                let _ = reply.send(answer);
+                return;
+            }
+            let resp = process_instruction(&mut engine, &ins, &context.metrics).unwrap();
+            if ins.is_write() && context.flush_after_write() {
+                if let Err(e) = engine.flush() {
+                    error!("Failed to flush the engine: {:?}", e);
+                }
+            }
+            reservation.record(&resp);
+            let _ = reply.send(Answer::Response(resp));
+        }))
+        .ok()?;
+    match context.options.op_timeout {
+        Some(timeout) => wait_reply(&replied, timeout, op, context),
+        None => replied.recv().ok(),
+    }
+}
+
+/// The reply of the pool, or an error once `timeout` passed.
+fn wait_reply(
+    replied: &Receiver<Answer>,
+    timeout: Duration,
+    op: &str,
+    context: &ConnectionContext,
+) -> Option<Answer> {
+    match replied.recv_timeout(timeout) {
+        Ok(resp) => Some(resp),
+        Err(RecvTimeoutError::Timeout) => {
+            warn!("Engine did not answer a {} within {:?}.", op, timeout);
+            context.metrics.record_error();
+            Some(Answer::Response(Response::Error(
+                "Engine timed out.".to_owned(),
+            )))
+        }
+        Err(RecvTimeoutError::Disconnected) => None,
+    }
+}
+
+fn serve_connection<T: KvsEngine>(engine: T, stream: Box<dyn Stream>, context: ConnectionContext) {
+    context.metrics.connection_opened();
+    let mut authenticated = context.options.auth_token.is_none();
+    let mut wrote = false;
+    // Smallest answer gzipped, once the client asked for it.
+    let mut compress_above = None;
+    // Chosen by the client along its first instruction.
+    let mut framing = None;
+    // Answers are encoded here, it keeps its capacity from one to the next.
+    let mut reply = Vec::new();
+    let mut buf_reader = BufReader::new(stream);
+    loop {
+        // Only an authenticated client can have asked for compression.
+        let compressed = authenticated && compress_above.is_some();
+        let ins = match read_instruction(&mut buf_reader, &mut framing, compressed) {
+            Ok(Some(ins)) => ins,
+            Ok(None) => break,
+            Err(e) if is_idle_timeout(&e) => {
+                info!("Close idle connection.");
+                break;
+            }
+            Err(e) => {
+                error!("Failed to read from client: {:#}", e);
+                break;
+            }
+        };
+        let reply_framing = framing.unwrap_or_default();
+        let _request = context.in_flight.enter();
+        if context.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let (request_id, ins) = ins.untraced();
+        let trace = request_id.as_deref().unwrap_or("-");
+        debug!("[client->server] [{}] {:?}", trace, ins);
+        context.metrics.record_op(&ins);
+        wrote |= ins.is_write();
+        let (resp, close) = match (&ins, &context.options.auth_token) {
+            (Instruction::Auth { token }, Some(expected)) => {
+                authenticated = token_matches(token, expected);
+                if authenticated {
+                    (Response::Ok("".to_owned()), false)
+                } else {
+                    warn!("Client sent a wrong auth token.");
+                    (Response::Error("Authentication failed.".to_owned()), true)
+                }
+            }
+            (_, Some(_)) if !authenticated => {
+                (Response::Error("Authentication required.".to_owned()), true)
+            }
+            (ins, _) if context.options.read_only && ins.is_write() => {
+                context.metrics.record_error();
+                (Response::Error("server is read-only".to_owned()), false)
+            }
+            (Instruction::Replicate { from }, _) => {
+                info!("Replica connected, streaming from {:?}.", from);
+                if let Err(e) = stream_changes(
+                    &engine,
+                    *from,
+                    buf_reader.get_mut(),
+                    reply_framing,
+                    &context,
+                ) {
+                    info!("Replication stopped: {}", e);
+                }
+                break;
+            }
+            (Instruction::ScanAll, _) => {
+                if let Err(e) = stream_scan(&engine, buf_reader.get_mut(), reply_framing) {
+                    context.metrics.record_error();
+                    info!("Scan aborted: {}", e);
+                    break;
+                }
+                continue;
+            }
+            (Instruction::Metrics, _) => (Response::Ok(context.metrics.render()), false),
+            (Instruction::Compress { .. }, _) if reply_framing != Framing::Lines => {
+                let refused = "Compression is only served over lines.".to_owned();
+                (Response::Error(refused), false)
+            }
+            (Instruction::Compress { min_size }, _) => {
+                // Answered uncompressed, the client switches on reading it.
+                let writer = buf_reader.get_mut();
+                let answered = write_message(writer, &Response::Ok("gzip".to_owned()))
+                    .and_then(|_| Ok(writer.flush()?));
+                if let Err(e) = answered {
+                    error!("Failed to answer the client: {}", e);
+                    break;
+                }
+                compress_above = Some(*min_size);
+                continue;
+            }
+            _ => {
+                // A `Get` is encoded by the pool, unless its answer is wrapped or compressed.
+                let encoding = match ins {
+                    Instruction::Get { .. } if request_id.is_none() && compress_above.is_none() => {
+                        Some((reply_framing, mem::take(&mut reply)))
+                    }
+                    _ => None,
+                };
+                match dispatch(&engine, ins, &context, encoding) {
+                    Some(Answer::Response(resp)) => (resp, false),
+                    Some(Answer::Encoded(encoded)) => {
+                        debug!("[server->client] [{}] {} bytes", trace, encoded.len());
+                        reply = encoded;
+                        if let Err(e) = send_reply(buf_reader.get_mut(), &mut reply) {
+                            error!("Failed to answer the client: {}", e);
+                            break;
+                        }
+                        continue;
+                    }
+                    None => {
+                        info!("Request dropped, server is shutting down.");
+                        break;
+                    }
+                }
+            }
+        };
+        debug!("[server->client] [{}] {:?}", trace, resp);
+        let resp = resp.traced(request_id);
+        let writer = buf_reader.get_mut();
+        let written = match compress_above {
+            Some(min_size) => {
+                write_message_compressed(writer, &resp, min_size).and_then(|_| Ok(writer.flush()?))
+            }
+            None => reply_framing
+                .encode(&mut reply, &resp)
+                .and_then(|_| send_reply(writer, &mut reply)),
+        };
+        if let Err(e) = written {
+            error!("Failed to answer the client: {}", e);
+            break;
+        }
+        if close {
+            break;
         }
     }
+    if wrote && context.options.flush_policy == FlushPolicy::OnDisconnect {
+        if let Err(e) = engine.flush() {
+            error!("Failed to flush the engine: {:?}", e);
+        }
+    }
+    context.metrics.connection_closed();
+}
+
+/// Write the answer encoded in `reply` and flush it, keeping at most `REPLY_BUFFER_KEPT`
+/// of its capacity for the next one.
+fn send_reply(writer: &mut (impl Write + ?Sized), reply: &mut Vec<u8>) -> Result<()> {
+    let written = writer.write_all(reply);
+    reply.shrink_to(REPLY_BUFFER_KEPT);
+    Ok(written.and_then(|_| writer.flush())?)
+}
+
+/// Read the next instruction, detecting the framing of the connection before the first one.
+/// A compressed one is refused unless `compressed`, so nothing is inflated for a client
+/// which didn't ask for compression.
+fn read_instruction(
+    reader: &mut impl BufRead,
+    framing: &mut Option<Framing>,
+    compressed: bool,
+) -> Result<Option<Instruction>> {
+    let framing = match framing {
+        Some(framing) => *framing,
+        None => match Framing::detect(reader)? {
+            Some(detected) => *framing.insert(detected),
+            None => return Ok(None),
+        },
+    };
+    if !compressed
+        && framing == Framing::Lines
+        && reader.fill_buf()?.first() == Some(&COMPRESSED_FRAME)
+    {
+        bail!("Compressed message before compression was negotiated.");
+    }
+    framing.read(reader)
+}
+
+/// Whether reading failed because the connection stayed idle past the read timeout.
+fn is_idle_timeout(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<std::io::Error>().map(std::io::Error::kind),
+        Some(ErrorKind::WouldBlock | ErrorKind::TimedOut)
+    )
+}
+
+/// Send the writes after `from` to a replica, then the new ones as they come,
+/// until the server shuts down or the replica leaves.
+fn stream_changes<T: KvsEngine>(
+    engine: &T,
+    mut from: Option<(FileID, FileOffset)>,
+    writer: &mut dyn Write,
+    framing: Framing,
+    context: &ConnectionContext,
+) -> Result<()> {
+    let mut resync = from.is_none();
+    while !context.shutdown.load(Ordering::SeqCst) {
+        if resync {
+            framing.write(writer, &ReplicationFrame::Resync)?;
+            from = None;
+            resync = false;
+        }
+        let changes = match engine.changes_from(from) {
+            Ok(changes) => changes,
+            Err(e) if matches!(e.downcast_ref(), Some(KvError::CursorCompacted(_))) => {
+                info!("Replica cursor {:?} was compacted away, resync.", from);
+                resync = true;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let mut sent = false;
+        for change in changes {
+            let change = match change {
+                Ok(change) => change,
+                Err(e) => {
+                    // The replica gets the reason, then reconnects from its cursor.
+                    error!("Failed to read the changes after {:?}: {:?}", from, e);
+                    framing.write(writer, &ReplicationFrame::Error(e.to_string()))?;
+                    writer.flush()?;
+                    return Err(e);
+                }
+            };
+            from = Some((change.file_id, change.next_offset));
+            framing.write(writer, &ReplicationFrame::Change(change))?;
+            sent = true;
+        }
+        if !sent {
+            framing.write(writer, &ReplicationFrame::Heartbeat)?;
+        }
+        writer.flush()?;
+        if !sent {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+/// Send every pair a page at a time, so the store is never loaded at once.
+/// An engine failure is reported to the client, only write failures are returned.
+fn stream_scan<T: KvsEngine>(engine: &T, writer: &mut dyn Write, framing: Framing) -> Result<()> {
+    let mut after = None;
+    loop {
+        let page = match engine.scan_after(after.as_deref(), SCAN_PAGE_SIZE) {
+            Ok(page) => page,
+            Err(e) => {
+                error!("Scan failed: {:?}", e);
+                framing.write(writer, &ScanFrame::Error(e.to_string()))?;
+                break;
+            }
+        };
+        let last_page = page.len() < SCAN_PAGE_SIZE;
+        for (key, value) in page {
+            framing.write(writer, &ScanFrame::Pair(key.clone(), value))?;
+            after = Some(key);
+        }
+        if last_page {
+            framing.write(writer, &ScanFrame::End)?;
+            break;
+        }
+        writer.flush()?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Compare tokens without bailing out on the first mismatched byte.
+pub(crate) fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
 }
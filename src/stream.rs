@@ -0,0 +1,41 @@
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+
+/// Byte stream a connection is carried on, plain TCP or TLS.
+pub(crate) trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Every address `addr` resolves to, failing with an error naming it if there is none
+/// rather than the "invalid socket address" of std.
+pub(crate) fn resolve(addr: &(impl ToSocketAddrs + Debug)) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = addr
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {:?}.", addr))?
+        .collect();
+    if addrs.is_empty() {
+        bail!("{:?} resolves to no socket address.", addr);
+    }
+    Ok(addrs)
+}
+
+/// Connect to the first address `addr` resolves to accepting the connection, in order.
+pub(crate) fn connect(addr: &(impl ToSocketAddrs + Debug)) -> Result<TcpStream> {
+    let candidates = resolve(addr)?;
+    let mut last_error = None;
+    for &candidate in &candidates {
+        match TcpStream::connect(candidate) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("Failed to connect to {}: {}", candidate, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    let e = last_error.map_or_else(|| anyhow!("No address to connect to."), Into::into);
+    Err(e.context(format!("Failed to connect to {:?}.", candidates)))
+}
@@ -1,4 +1,9 @@
 //! Different implement of thread pool, used in connection dispatching.
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+
+use log::error;
+
 pub use naive_pool::NaiveThreadPool;
 pub use rayon_pool::RayonAdapterPool as RayonThreadPool;
 pub use shared_pool::SharedQueueThreadPool;
@@ -7,10 +12,55 @@ mod naive_pool;
 mod rayon_pool;
 mod shared_pool;
 
+/// What a panicking job does to the worker running it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Catch the panic and carry on with the next job, the pool keeps its size.
+    #[default]
+    Respawn,
+    /// Abort the process.
+    Abort,
+}
+
+/// Options shared by the pools, see `ThreadPool::new_with_config`.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Worker threads, the naive pool starts one per job whatever this says.
+    pub threads: u32,
+    /// Workers are named this followed by their number, counted from 1.
+    /// Unset, each pool names them as it always did.
+    pub thread_name_prefix: Option<String>,
+    /// What a panicking job does to its worker.
+    pub panic_policy: PanicPolicy,
+}
+
+impl PoolConfig {
+    /// The defaults with `threads` workers.
+    pub fn new(threads: u32) -> Self {
+        Self {
+            threads,
+            thread_name_prefix: None,
+            panic_policy: PanicPolicy::default(),
+        }
+    }
+
+    fn thread_name(&self, number: usize) -> Option<String> {
+        let prefix = self.thread_name_prefix.as_ref()?;
+        Some(format!("{}{}", prefix, number))
+    }
+}
+
 /// Common trait defined for thread pool.
 pub trait ThreadPool {
     /// Crate a new instance.
     fn new(threads: u32) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::new_with_config(PoolConfig::new(threads))
+    }
+    /// Crate a new instance with `config`.
+    fn new_with_config(config: PoolConfig) -> anyhow::Result<Self>
     where
         Self: Sized;
     /// Create a new thread.
@@ -18,3 +68,18 @@ pub trait ThreadPool {
     where
         F: FnOnce() + Send + 'static;
 }
+
+/// Run `job`, logging its panic if any instead of unwinding further.
+fn catch_panic(job: impl FnOnce()) {
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        error!("A job panicked.");
+    }
+}
+
+/// Run `job`, aborting the process if it panics.
+fn abort_on_panic(job: impl FnOnce()) {
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        error!("A job panicked, aborting.");
+        process::abort();
+    }
+}
@@ -1,21 +1,39 @@
-use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::Builder;
 
 use anyhow::Result;
 
-use super::ThreadPool;
+use super::{abort_on_panic, catch_panic, PanicPolicy, PoolConfig, ThreadPool};
 
 /// A naive implemention of thread poll.
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    config: PoolConfig,
+    spawned: AtomicUsize,
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_threads: u32) -> Result<Self> {
-        Ok(Self)
+    fn new_with_config(config: PoolConfig) -> Result<Self> {
+        Ok(Self {
+            config,
+            spawned: AtomicUsize::new(0),
+        })
     }
 
+    /// Each job gets a thread of its own, so there is no worker to keep: a panic is
+    /// caught under `PanicPolicy::Respawn`, ending only the thread of the job.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let number = self.spawned.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut builder = Builder::new();
+        if let Some(name) = self.config.thread_name(number) {
+            builder = builder.name(name);
+        }
+        let spawned = match self.config.panic_policy {
+            PanicPolicy::Abort => builder.spawn(move || abort_on_panic(job)),
+            PanicPolicy::Respawn => builder.spawn(move || catch_panic(job)),
+        };
+        spawned.expect("Failed to spawn a thread");
     }
 }
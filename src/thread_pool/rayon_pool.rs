@@ -1,7 +1,10 @@
+use std::process;
+
 use anyhow::Result;
+use log::error;
 use rayon::{ThreadPool as RayonThreadPool, ThreadPoolBuilder};
 
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{PanicPolicy, PoolConfig, ThreadPool};
 
 ///
 pub struct RayonAdapterPool {
@@ -9,15 +12,25 @@ pub struct RayonAdapterPool {
 }
 
 impl ThreadPool for RayonAdapterPool {
-    fn new(threads: u32) -> Result<Self>
+    fn new_with_config(config: PoolConfig) -> Result<Self>
     where
         Self: Sized,
     {
+        let panic_policy = config.panic_policy;
+        let mut builder = ThreadPoolBuilder::new()
+            .num_threads(config.threads as usize)
+            .panic_handler(move |_| match panic_policy {
+                PanicPolicy::Abort => {
+                    error!("A job panicked, aborting.");
+                    process::abort()
+                }
+                PanicPolicy::Respawn => error!("A job panicked."),
+            });
+        if config.thread_name_prefix.is_some() {
+            builder = builder.thread_name(move |idx| config.thread_name(idx + 1).unwrap());
+        }
         Ok(RayonAdapterPool {
-            pool: ThreadPoolBuilder::new()
-                .num_threads(threads as usize)
-                .build()
-                .unwrap(),
+            pool: builder.build().unwrap(),
         })
     }
 
@@ -6,9 +6,8 @@ use crossbeam::channel::unbounded;
 use crossbeam::channel::Receiver;
 use crossbeam::channel::Sender;
 use log::error;
-use log::warn;
 
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{abort_on_panic, catch_panic, PanicPolicy, PoolConfig, ThreadPool};
 
 type TaskClosure = Box<dyn FnOnce() + Send + 'static>;
 
@@ -17,26 +16,11 @@ enum TaskMessage {
     Shutdown,
 }
 
-struct WorkerGuard(Receiver<TaskMessage>);
-
-impl Drop for WorkerGuard {
-    fn drop(&mut self) {
-        if thread::panicking() {
-            let name = thread::current().name().unwrap().to_string();
-            warn!("Thread: {} panics, start a reliever.", name);
-            let rx = self.0.clone();
-            Builder::new()
-                .name(name)
-                .spawn(move || thread_main_loop(WorkerGuard(rx)))
-                .unwrap();
-        }
-    }
-}
-
 /// A simple thread pool implement by channel.
 pub struct SharedQueueThreadPool {
     tx: Sender<TaskMessage>,
     threads: Vec<JoinHandle<()>>,
+    panic_policy: PanicPolicy,
 }
 
 impl Drop for SharedQueueThreadPool {
@@ -52,8 +36,10 @@ impl Drop for SharedQueueThreadPool {
     }
 }
 
-fn thread_main_loop(guard: WorkerGuard) {
-    while let Ok(message) = guard.0.recv() {
+/// Run the tasks of `rx` until shutdown, a panicking task is caught or aborts the process
+/// as `spawn` wrapped it, so the worker outlives it.
+fn thread_main_loop(rx: Receiver<TaskMessage>) {
+    while let Ok(message) = rx.recv() {
         match message {
             TaskMessage::NewTask(task) => task(),
             TaskMessage::Shutdown => return,
@@ -67,23 +53,28 @@ fn thread_main_loop(guard: WorkerGuard) {
 }
 
 impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self>
+    fn new_with_config(config: PoolConfig) -> Result<Self>
     where
         Self: Sized,
     {
         let (tx, rx) = unbounded();
-        let thread_handler: Vec<_> = (0..threads)
+        let panic_policy = config.panic_policy;
+        let thread_handler: Vec<_> = (0..config.threads as usize)
             .map(|idx| {
                 let rx = rx.clone();
+                let name = config
+                    .thread_name(idx + 1)
+                    .unwrap_or_else(|| format!("SharedQueueThreadPool-thread: {}", idx + 1));
                 Builder::new()
-                    .name(format!("SharedQueueThreadPool-thread: {}", idx + 1))
-                    .spawn(move || thread_main_loop(WorkerGuard(rx)))
+                    .name(name)
+                    .spawn(move || thread_main_loop(rx))
             })
             .collect::<Result<Vec<_>, _>>()
             .expect("Failed to spawn the working threads in thread pool");
         Ok(Self {
             tx,
             threads: thread_handler,
+            panic_policy,
         })
     }
 
@@ -91,7 +82,11 @@ impl ThreadPool for SharedQueueThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        match self.tx.send(TaskMessage::NewTask(Box::new(job))) {
+        let task: TaskClosure = match self.panic_policy {
+            PanicPolicy::Abort => Box::new(move || abort_on_panic(job)),
+            PanicPolicy::Respawn => Box::new(move || catch_panic(job)),
+        };
+        match self.tx.send(TaskMessage::NewTask(task)) {
             Ok(_) => (),
             Err(e) => panic!("{:}", e.to_string()),
         }
@@ -0,0 +1,75 @@
+//! TLS configuration shared by `KvServer` and `KvClient`.
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+};
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open certificate file {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("Failed to parse certificate file {:?}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {:?}", path)
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open private key file {:?}", path))?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Failed to parse private key file {:?}", path))?
+        {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key found in {:?}", path)),
+        }
+    }
+}
+
+/// Build the server side config from PEM encoded certificate chain and private key.
+pub(crate) fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+        .context("Invalid certificate or private key.")?;
+    Ok(Arc::new(config))
+}
+
+/// Build the client side config, trusting `ca` if provided, the webpki roots otherwise.
+pub(crate) fn client_config(ca: Option<&Path>) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("Invalid CA certificate in {:?}", ca))?;
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
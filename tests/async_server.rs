@@ -0,0 +1,96 @@
+#![cfg(feature = "async")]
+
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+use kvs::engine::KvStore;
+use kvs::protocol::Framing;
+use kvs::{AsyncKvServer, Instruction, KvClient, Response, Result};
+
+async fn request(stream: &mut BufReader<TcpStream>, ins: &Instruction) -> Result<Response> {
+    let mut json = serde_json::to_vec(ins)?;
+    json.push(b'\n');
+    stream.get_mut().write_all(&json).await?;
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+// Many more clients than engine calls allowed at once, each sees its own writes.
+#[test]
+fn many_async_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4122";
+    let server = AsyncKvServer::new(KvStore::open(temp_dir.path())?, addr)?.with_max_in_flight(4);
+    let runtime = Runtime::new()?;
+    runtime.spawn(server.run());
+
+    let clients: Vec<_> = (0..200)
+        .map(|client| {
+            runtime.spawn(async move {
+                let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+                for i in 0..10 {
+                    let key = format!("key{}-{}", client, i);
+                    let set = Instruction::Set {
+                        key: key.clone(),
+                        value: format!("value{}", i),
+                        idempotency_key: None,
+                    };
+                    assert!(matches!(request(&mut stream, &set).await?, Response::Ok(_)));
+                    let resp = request(&mut stream, &Instruction::Get { key }).await?;
+                    assert!(matches!(resp, Response::Ok(value) if value == format!("value{}", i)));
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+        })
+        .collect();
+    for client in clients {
+        runtime.block_on(client)??;
+    }
+
+    // The blocking client works unchanged.
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(
+        client.get("key199-9".to_owned())?.as_deref(),
+        Some("value9")
+    );
+    assert_eq!(client.get("missing".to_owned())?, None);
+    client.remove("key0-0".to_owned())?;
+    assert_eq!(client.scan_all()?.len(), 200 * 10 - 1);
+
+    // Compression is refused, the client stays uncompressed.
+    let mut client = KvClient::connect_with_compression(addr, 16)?;
+    client.set("key0-0".to_owned(), "v".repeat(1000))?;
+    assert_eq!(client.get("key0-0".to_owned())?, Some("v".repeat(1000)));
+    Ok(())
+}
+
+// Clients of `KvServer` get the same answers, authenticated, length-prefixed or retrying sets
+// with an idempotency key.
+#[test]
+fn kv_server_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4133";
+    let server =
+        AsyncKvServer::new(KvStore::open(temp_dir.path())?, addr)?.with_auth_token("secret");
+    let runtime = Runtime::new()?;
+    runtime.spawn(server.run());
+
+    let mut client = KvClient::connect(addr)?;
+    let err = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Authentication required.");
+
+    let mut client = KvClient::connect_with_framing(addr, Framing::LengthPrefixed)?;
+    client.authenticate("secret")?;
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), "req1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), "req1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value2"));
+    assert_eq!(
+        client.scan_all()?,
+        vec![("key1".to_owned(), "value2".to_owned())]
+    );
+    Ok(())
+}
@@ -131,6 +131,40 @@ fn client_cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client --format json` reports failures as a JSON object.
+#[test]
+fn client_cli_json_error() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "get",
+            "key1",
+            "--addr",
+            "127.0.0.1:4099",
+            "--format",
+            "json",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(contains(r#""ok":false"#));
+}
+
+// `kvs-client` exits with 2 and reports on stderr when the server is unreachable.
+#[test]
+fn client_cli_connection_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key1", "--addr", "127.0.0.1:4099"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains("Cannot reach the server at 127.0.0.1:4099"));
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
@@ -214,6 +248,31 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+fn cli_detected_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // Without `--engine` the server keeps to sled rather than refusing the data.
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().expect("server exited before killed");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -226,6 +285,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // Reap it, so the engine lock is released before a restart.
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -274,7 +335,8 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
+        .code(1)
+        .stdout(is_empty())
         .stderr(contains("Key: key2 not found"));
 
     Command::cargo_bin("kvs-client")
@@ -307,6 +369,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // Reap it, so the engine lock is released before a restart.
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -337,3 +401,146 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-server` should exit cleanly on SIGTERM and keep the data written before.
+#[test]
+fn server_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let stdout_path = temp_dir.path().join("stdout");
+    let addr = "127.0.0.1:4006";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    let status = child.wait().expect("server is not running");
+    assert!(status.success());
+    let content = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
+    assert!(content.contains("shutting down gracefully"));
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+// Servers sharing a working directory keep their data apart with `--data-dir`.
+#[test]
+fn server_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let servers: Vec<_> = [("127.0.0.1:4007", "data1"), ("127.0.0.1:4008", "data2")]
+        .iter()
+        .map(|(addr, dir)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--addr", addr, "--data-dir", dir])
+                .current_dir(&temp_dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key: key1 not found"));
+    for mut server in servers {
+        server.kill().expect("server exited before killed");
+    }
+
+    assert!(temp_dir.path().join("data1").join(".engine_mark").exists());
+    assert!(temp_dir.path().join("data2").join(".engine_mark").exists());
+    assert!(!temp_dir.path().join(".engine_mark").exists());
+}
+
+// `kvs-server --config` takes its settings from the file, flags take precedence.
+#[test]
+fn server_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("server.toml"),
+        r#"
+addr = "127.0.0.1:4009"
+engine = "sled"
+threads = 2
+log_level = "info"
+data_dir = "data"
+"#,
+    )
+    .unwrap();
+    let stdout_path = temp_dir.path().join("stdout");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "server.toml"])
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let mark = fs::read_to_string(temp_dir.path().join("data").join(".engine_mark")).unwrap();
+    assert_eq!(mark, "sled");
+    let content = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
+    assert!(content.contains("127.0.0.1:4009"));
+    assert!(!content.contains("DEBUG"));
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "server.toml", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
@@ -0,0 +1,71 @@
+//! In a binary of its own, as the allocator counts the allocations of every thread.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tempfile::TempDir;
+
+use kvs::engine::{KvStore, KvStoreConfig};
+use kvs::{KvsEngine, Result};
+
+/// Tracks the bytes allocated and their peak since the last `reset_peak`.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Bytes allocated now, also the peak from now on.
+fn reset_peak() -> usize {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(allocated, Ordering::Relaxed);
+    allocated
+}
+
+// Compaction copies the keys in batches, rather than a snapshot of the whole index.
+#[test]
+fn compaction_memory_bounded() -> Result<()> {
+    const KEYS: usize = 50_000;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for round in 0..2 {
+        for i in 0..KEYS {
+            store.set(&format!("key{:08}", i), &format!("value{}", round))?;
+        }
+    }
+
+    let before = reset_peak();
+    store.compact()?;
+    let extra = PEAK.load(Ordering::Relaxed) - before;
+    // Copying the whole index, as compaction used to, takes over 8MB here.
+    assert!(extra < 1 << 20, "compaction took {} bytes", extra);
+
+    for i in (0..KEYS).step_by(997) {
+        assert_eq!(
+            store.get(&format!("key{:08}", i))?,
+            Some("value1".to_owned())
+        );
+    }
+    Ok(())
+}
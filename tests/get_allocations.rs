@@ -0,0 +1,88 @@
+//! In a binary of its own, as the allocator counts the allocations of every thread.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use tempfile::TempDir;
+
+use kvs::engine::{KvStore, KvStoreConfig};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvServer, KvsEngine, Result};
+
+/// Counts the bytes allocated.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const VALUE_LEN: usize = 32 * 1024;
+const GETS: usize = 100;
+
+/// Bytes allocated by the client and a server over `config` while answering `GETS` gets of
+/// one value, the reply buffers warmed up first.
+fn get_allocations(addr: &str, config: KvStoreConfig) -> Result<usize> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1", &"v".repeat(VALUE_LEN))?;
+    let server = KvServer::new(store, SharedQueueThreadPool::new(2)?, addr)?;
+    let handle = server.handle()?;
+    let server = thread::spawn(move || server.run());
+
+    let request = b"{\"Get\":{\"key\":\"key1\"}}\n";
+    // `{"Ok":"` and `"}` around the value, then the newline.
+    let mut reply = vec![0; VALUE_LEN + 10];
+    let mut client = TcpStream::connect(addr)?;
+    client.write_all(request)?;
+    client.read_exact(&mut reply)?;
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    for _ in 0..GETS {
+        client.write_all(request)?;
+        client.read_exact(&mut reply)?;
+    }
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - before;
+    assert!(reply.ends_with(b"vv\"}\n"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()?;
+    Ok(allocated)
+}
+
+// A value found in the cache goes to the socket without being copied on the way.
+#[test]
+fn get_cached_value_without_copy() -> Result<()> {
+    let uncached = get_allocations("127.0.0.1:4300", KvStoreConfig::default())?;
+    let cached = get_allocations(
+        "127.0.0.1:4301",
+        KvStoreConfig {
+            value_cache_size: Some(1 << 20),
+            ..Default::default()
+        },
+    )?;
+    assert!(
+        uncached >= GETS * VALUE_LEN,
+        "{} bytes allocated reading from the log",
+        uncached
+    );
+    assert!(
+        cached < GETS * VALUE_LEN / 8,
+        "{} bytes allocated reading from the cache",
+        cached
+    );
+    Ok(())
+}
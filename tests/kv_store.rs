@@ -1,11 +1,18 @@
-use std::sync::{Arc, Barrier};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::engine::KvStore;
-use kvs::{KvsEngine, Result};
+use kvs::engine::{
+    detect_engine, AccessHook, Capacity, Clock, CommandSummary, Durability, EvictionPolicy,
+    IntegrityIssue, KvStore, KvStoreConfig, LatencyReport, ShardedKvStore, SledAdapter, WriteOp,
+    ENGINE_MARK_FILE,
+};
+use kvs::{EngineType, KvError, KvsEngine, Result};
 
 // Should get previously stored value
 #[test]
@@ -84,6 +91,1390 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+fn count_prefix_on(store: impl KvsEngine) -> Result<()> {
+    for (key, value) in &[
+        ("user:1", "alice"),
+        ("user:2", "bob"),
+        ("user:3", "carol"),
+        ("order:1", "book"),
+        ("order:2", "pen"),
+        ("misc", "x"),
+    ] {
+        store.set(key, value)?;
+    }
+    store.remove("user:2")?;
+
+    assert_eq!(store.count_prefix("user:")?, 2);
+    assert_eq!(store.count_prefix("order:")?, 2);
+    assert_eq!(store.count_prefix("")?, 5);
+    assert_eq!(store.count_prefix("nothing")?, 0);
+    Ok(())
+}
+
+#[test]
+fn count_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    count_prefix_on(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn count_prefix_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    count_prefix_on(SledAdapter::open(temp_dir.path())?)
+}
+
+fn replace_on(store: impl KvsEngine) -> Result<()> {
+    store.set("key1", "value1")?;
+    store.replace("key1", "value2")?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    let err = store.replace("key2", "value2").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvError>(),
+        Some(&KvError::KeyNotFound("key2".to_owned()))
+    );
+    assert_eq!(store.get("key2")?, None);
+    Ok(())
+}
+
+#[test]
+fn replace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    replace_on(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn replace_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    replace_on(SledAdapter::open(temp_dir.path())?)
+}
+
+fn update_on(store: impl KvsEngine) -> Result<()> {
+    store.update("counter", |value| {
+        assert_eq!(value, None);
+        Some("1".to_owned())
+    })?;
+    store.update("counter", |value| value.map(|v| v + "2"))?;
+    assert_eq!(store.get("counter")?, Some("12".to_owned()));
+
+    // A panicking callback leaves the store usable and the value untouched.
+    let store_ref = &store;
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        store_ref.update("counter", |_| panic!("bad callback"))
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(store.get("counter")?, Some("12".to_owned()));
+
+    store.update("counter", |_| None)?;
+    assert_eq!(store.get("counter")?, None);
+
+    let thread_num = 8;
+    let appends = 50;
+    let handles: Vec<_> = (0..thread_num)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for j in 0..appends {
+                    store
+                        .update("list", |value| {
+                            Some(format!("{}[{}.{}]", value.unwrap_or_default(), i, j))
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let list = store.get("list")?.unwrap();
+    for i in 0..thread_num {
+        for j in 0..appends {
+            assert!(list.contains(&format!("[{}.{}]", i, j)));
+        }
+    }
+    assert_eq!(list.matches('[').count(), thread_num * appends);
+    Ok(())
+}
+
+#[test]
+fn update() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    update_on(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn update_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    update_on(SledAdapter::open(temp_dir.path())?)
+}
+
+fn first_and_last_key_on(store: impl KvsEngine) -> Result<()> {
+    assert_eq!(store.first_key()?, None);
+    assert_eq!(store.last_key()?, None);
+    for key in &["m", "b", "z", "a", "y"] {
+        store.set(key, "value")?;
+    }
+    store.remove("z")?;
+
+    assert_eq!(store.first_key()?, Some("a".to_owned()));
+    assert_eq!(store.last_key()?, Some("y".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn first_and_last_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    first_and_last_key_on(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn first_and_last_key_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    first_and_last_key_on(SledAdapter::open(temp_dir.path())?)
+}
+
+fn remove_prefix_on(store: impl KvsEngine) -> Result<()> {
+    for (key, value) in &[
+        ("user:1", "alice"),
+        ("user:2", "bob"),
+        ("order:1", "book"),
+        ("misc", "x"),
+    ] {
+        store.set(key, value)?;
+    }
+
+    assert_eq!(store.remove_prefix("user:")?, 2);
+    assert_eq!(store.remove_prefix("nothing")?, 0);
+    assert_eq!(store.get("user:1")?, None);
+    assert_eq!(store.get("user:2")?, None);
+    assert_eq!(store.get("order:1")?, Some("book".to_owned()));
+    assert_eq!(store.get("misc")?, Some("x".to_owned()));
+    assert_eq!(store.remove_prefix("user:")?, 0);
+    Ok(())
+}
+
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_prefix_on(KvStore::open(temp_dir.path())?)?;
+
+    // Tombstones survive a reopen.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count_prefix("user:")?, 0);
+    assert_eq!(store.count_prefix("")?, 2);
+    Ok(())
+}
+
+#[test]
+fn remove_prefix_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_prefix_on(SledAdapter::open(temp_dir.path())?)
+}
+
+// The engine suite passes whichever index backs the store, the in-memory one by default.
+#[test]
+fn index_backends() -> Result<()> {
+    let suites: [fn(KvStore) -> Result<()>; 5] = [
+        count_prefix_on,
+        replace_on,
+        update_on,
+        first_and_last_key_on,
+        remove_prefix_on,
+    ];
+    for spill_threshold in [None, Some(2)] {
+        let config = KvStoreConfig {
+            index_spill_threshold: spill_threshold,
+            ..Default::default()
+        };
+        for suite in suites {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            suite(KvStore::open_with_config(temp_dir.path(), config.clone())?)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn sharded_routing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs: Vec<_> = (0..3)
+        .map(|i| temp_dir.path().join(i.to_string()))
+        .collect();
+    let store = ShardedKvStore::open(&dirs)?;
+    let keys: Vec<_> = (0..30).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        store.set(key, "value")?;
+    }
+    let shards: Vec<_> = keys.iter().map(|key| store.shard_of(key)).collect();
+    assert!((0..3).all(|shard| shards.contains(&shard)));
+    drop(store);
+
+    let store = ShardedKvStore::open(&dirs)?;
+    for (key, &shard) in keys.iter().zip(&shards) {
+        assert_eq!(store.shard_of(key), shard);
+        assert_eq!(store.get(key)?, Some("value".to_owned()));
+    }
+    drop(store);
+
+    // Each key lives in its own shard only.
+    for (i, dir) in dirs.iter().enumerate() {
+        let shard = KvStore::open(dir)?;
+        for (key, &expected) in keys.iter().zip(&shards) {
+            assert_eq!(shard.get(key)?.is_some(), expected == i);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn sharded_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open((0..4).map(|i| temp_dir.path().join(i.to_string())))?;
+    for i in 0..20 {
+        store.set(&format!("a{:02}", i), &i.to_string())?;
+        store.set(&format!("b{:02}", i), &i.to_string())?;
+    }
+    store.remove("a05")?;
+
+    let scanned = store.scan_prefix("a")?;
+    let expected: Vec<_> = (0..20)
+        .filter(|&i| i != 5)
+        .map(|i| (format!("a{:02}", i), i.to_string()))
+        .collect();
+    assert_eq!(scanned, expected);
+    assert_eq!(store.count_prefix("b")?, 20);
+    assert_eq!(store.first_key()?, Some("a00".to_owned()));
+    assert_eq!(store.last_key()?, Some("b19".to_owned()));
+    assert_eq!(store.remove_prefix("b")?, 20);
+    assert_eq!(store.count_prefix("")?, 19);
+    store.compact()?;
+    assert_eq!(store.scan_prefix("")?, expected);
+    Ok(())
+}
+
+/// Sets `cancel` when `key` is read, counting the reads.
+struct CancelAt {
+    key: &'static str,
+    cancel: Arc<AtomicBool>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl AccessHook for CancelAt {
+    fn before_get(&self, key: &str) -> Result<()> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        if key == self.key {
+            self.cancel.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+// Setting the flag stops a scan still running with `KvError::Cancelled`, before it reads
+// another key.
+#[test]
+fn cancelled_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..50_000 {
+        store.set(&format!("key{:05}", i), "value")?;
+    }
+    let cancel = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicUsize::new(0));
+    store.set_access_hook(Some(Box::new(CancelAt {
+        key: "key25000",
+        cancel: cancel.clone(),
+        reads: reads.clone(),
+    })))?;
+    let err = store.scan_prefix_cancellable("key", &cancel).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvError::Cancelled));
+    assert_eq!(reads.load(Ordering::SeqCst), 25_001);
+
+    cancel.store(false, Ordering::SeqCst);
+    assert_eq!(store.scan_prefix_cancellable("key0000", &cancel)?.len(), 10);
+    Ok(())
+}
+
+// Latencies are counted per operation once enabled, and not at all otherwise.
+#[test]
+fn latency_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    assert_eq!(store.latency_report(), LatencyReport::default());
+    drop(store);
+
+    let config = KvStoreConfig {
+        record_latency: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..100 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    for i in 0..50 {
+        store.get(&format!("key{}", i))?;
+    }
+    for i in 0..10 {
+        store.try_get(&format!("key{}", i))?;
+    }
+    store.remove("key1")?;
+    assert!(store.remove("key1").is_err());
+
+    let report = store.clone().latency_report();
+    assert_eq!(report.set.count, 100);
+    assert_eq!(report.get.count, 60);
+    assert_eq!(report.remove.count, 2);
+    for op in [report.get, report.set, report.remove] {
+        assert!(op.p50 <= op.p99 && op.p99 <= op.max);
+        assert!(op.max > Duration::ZERO);
+    }
+    Ok(())
+}
+
+// Draining hands back what the store held and empties it, losing no concurrent write.
+#[test]
+fn drain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(&format!("key{:03}", i), &i.to_string())?;
+    }
+    store.remove("key050")?;
+    let before = store.scan_prefix("")?;
+    assert_eq!(store.drain()?, before);
+    assert_eq!(store.count_prefix("")?, 0);
+    assert_eq!(store.get("key001")?, None);
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..500 {
+                store.set(&format!("w{:03}", i), "value")?;
+            }
+            Ok(())
+        })
+    };
+    let mut drained = Vec::new();
+    while !writer.is_finished() {
+        drained.extend(store.drain()?);
+    }
+    writer.join().unwrap()?;
+    drained.extend(store.drain()?);
+    let keys: Vec<_> = drained.into_iter().map(|(key, _)| key).collect();
+    let expected: Vec<_> = (0..500).map(|i| format!("w{:03}", i)).collect();
+    assert_eq!(keys, expected);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count_prefix("")?, 0);
+    Ok(())
+}
+
+// Paging with `scan_after` walks every engine in key order without repeats.
+#[test]
+fn scan_after() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        for i in (0..25).rev() {
+            store.set(&format!("key{:02}", i), &i.to_string())?;
+        }
+        store.remove("key07")?;
+        let mut scanned = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store.scan_after(after.as_deref(), 10)?;
+            after = page.last().map(|(key, _)| key.clone());
+            scanned.extend(page);
+            if after.is_none() {
+                break;
+            }
+        }
+        let expected: Vec<_> = (0..25)
+            .filter(|&i| i != 7)
+            .map(|i| (format!("key{:02}", i), i.to_string()))
+            .collect();
+        assert_eq!(scanned, expected);
+        Ok(())
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path().join("kvs"))?)?;
+    check(SledAdapter::open(temp_dir.path().join("sled"))?)?;
+    check(ShardedKvStore::open(
+        (0..3).map(|i| temp_dir.path().join(i.to_string())),
+    )?)
+}
+
+// Pages of keys cover the keyspace once each, the cursor ends with the last page.
+#[test]
+fn keys_paginated() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        for i in (0..31).rev() {
+            store.set(&format!("key{:02}", i), "value")?;
+        }
+        store.remove("key30")?;
+        let mut keys = Vec::new();
+        let mut after = None;
+        let mut pages = 0;
+        loop {
+            let (page, next) = store.keys_paginated(after.as_deref(), 10)?;
+            assert!(page.len() <= 10);
+            keys.extend(page);
+            pages += 1;
+            after = next;
+            if after.is_none() {
+                break;
+            }
+        }
+        let expected: Vec<_> = (0..30).map(|i| format!("key{:02}", i)).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(pages, 3);
+        assert!(store.keys_paginated(None, 0).is_err());
+        Ok(())
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path().join("kvs"))?)?;
+    check(SledAdapter::open(temp_dir.path().join("sled"))?)?;
+    check(ShardedKvStore::open(
+        (0..3).map(|i| temp_dir.path().join(i.to_string())),
+    )?)
+}
+
+// The bloom filter never hides a key, across growth, removals, compaction and reopening.
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        bloom_filter: true,
+        index_spill_threshold: Some(256),
+        ..Default::default()
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..1500 {
+            let key = format!("key{}", i);
+            let expected = if i % 3 == 0 {
+                None
+            } else {
+                Some(i.to_string())
+            };
+            assert_eq!(store.get(&key)?, expected);
+            assert_eq!(store.contains_key(&key)?, expected.is_some());
+        }
+        assert!(!store.contains_key("missing")?);
+        Ok(())
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..1500 {
+        store.set(&format!("key{}", i), &i.to_string())?;
+    }
+    for i in (0..1500).step_by(3) {
+        store.remove(&format!("key{}", i))?;
+    }
+    assert!(store.remove("key0").is_err());
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    check(&KvStore::open_with_config(temp_dir.path(), config)?)
+}
+
+// Both engines apply a transaction whole or not at all.
+#[test]
+fn transaction() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        store.set("key1", "value1")?;
+        let ops = vec![
+            WriteOp::Set {
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+            },
+            WriteOp::Remove {
+                key: "key2".to_owned(),
+            },
+            WriteOp::Remove {
+                key: "key2".to_owned(),
+            },
+        ];
+        let err = store.transaction(ops.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KvError>(),
+            Some(&KvError::KeyNotFound("key2".to_owned()))
+        );
+        assert_eq!(store.get("key2")?, None);
+
+        store.transaction(ops[..2].to_vec())?;
+        store.transaction(vec![WriteOp::Remove {
+            key: "key1".to_owned(),
+        }])?;
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key2")?, None);
+        Ok(())
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path().join("kvs"))?)?;
+    check(SledAdapter::open(temp_dir.path().join("sled"))?)
+}
+
+// A transaction cut off before its commit record is dropped whole on open,
+// and the log is written after it.
+#[test]
+fn torn_transaction() -> Result<()> {
+    for cut_in_write in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1", "value1")?;
+        store.transaction(vec![
+            WriteOp::Set {
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+            },
+            WriteOp::Remove {
+                key: "key1".to_owned(),
+            },
+            WriteOp::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned(),
+            },
+        ])?;
+        let commands = store.dump_commands()?;
+        let (file_id, commit, summary) = commands[commands.len() - 1].clone();
+        assert_eq!(summary, CommandSummary::Commit);
+        let (_, last_write, _) = commands[commands.len() - 2].clone();
+        // Simulate a crash, skipping every flush on drop.
+        std::mem::forget(store);
+
+        let cut = if cut_in_write { last_write + 5 } else { commit };
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(temp_dir.path().join(format!("{:05}.log", file_id)))?
+            .set_len(cut)?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?.as_deref(), Some("value1"));
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key3")?, None);
+        store.set("key4", "value4")?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?.as_deref(), Some("value1"));
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key4")?.as_deref(), Some("value4"));
+    }
+    Ok(())
+}
+
+// Purging leaves one insertion per live key and no tombstone.
+#[test]
+fn purge_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    store.set("key0", "value2")?;
+    for i in 1..4 {
+        store.remove(&format!("key{}", i))?;
+    }
+    // 1 superseded insertion, 3 removed insertions and their 3 tombstones.
+    assert_eq!(store.purge_tombstones()?, 7);
+
+    let summaries: Vec<_> = store
+        .dump_commands()?
+        .into_iter()
+        .map(|(_, _, summary)| summary)
+        .collect();
+    assert_eq!(
+        summaries,
+        vec![
+            CommandSummary::Insertion {
+                key: "key0".to_owned(),
+                value_len: 6
+            },
+            CommandSummary::Insertion {
+                key: "key4".to_owned(),
+                value_len: 5
+            },
+        ]
+    );
+    assert_eq!(store.purge_tombstones()?, 0);
+    Ok(())
+}
+
+#[test]
+fn dump_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value22")?;
+    store.remove("key1")?;
+
+    let dump = store.dump_commands()?;
+    let summaries: Vec<_> = dump.iter().map(|(_, _, summary)| summary.clone()).collect();
+    assert_eq!(
+        summaries,
+        vec![
+            CommandSummary::Insertion {
+                key: "key1".to_owned(),
+                value_len: 6
+            },
+            CommandSummary::Insertion {
+                key: "key1".to_owned(),
+                value_len: 7
+            },
+            CommandSummary::Discard {
+                key: "key1".to_owned()
+            },
+        ]
+    );
+    assert!(dump
+        .windows(2)
+        .all(|pair| (pair[0].0, pair[0].1) < (pair[1].0, pair[1].1)));
+    Ok(())
+}
+
+/// Records written before schema versioning, then in the current schema.
+const OLD_SCHEMA_LOG: &str = "{\"Insertion\":{\"key\":\"a\",\"value\":\"1\"}}\n\
+    {\"Insertion\":{\"key\":\"b\",\"value\":\"2\"}}\n\
+    {\"Discard\":{\"key\":\"b\"}}\n";
+const NEW_SCHEMA_LOG: &str = "v2{\"Insertion\":{\"key\":\"a\",\"value\":\"1\"}}\n\
+    v2{\"Insertion\":{\"key\":\"b\",\"value\":\"2\",\"ts\":1}}\n\
+    v2{\"Discard\":{\"key\":\"b\"}}\n";
+
+// Logs of either schema, or both mixed, replay the same way. Newer ones are refused.
+#[test]
+fn command_schema_versions() -> Result<()> {
+    let mixed = format!(
+        "{}{}",
+        OLD_SCHEMA_LOG,
+        NEW_SCHEMA_LOG.replace("\"a\"", "\"c\"")
+    );
+    for log in &[OLD_SCHEMA_LOG, NEW_SCHEMA_LOG, mixed.as_str()] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        drop(KvStore::open(temp_dir.path())?);
+        std::fs::write(temp_dir.path().join("00000.log"), log)?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("a")?, Some("1".to_owned()));
+        assert_eq!(store.get("b")?, None);
+        assert_eq!(store.dump_commands()?.len(), log.lines().count());
+
+        store.set("b", "3")?;
+        store.compact()?;
+        assert_eq!(store.get("a")?, Some("1".to_owned()));
+        assert_eq!(store.get("b")?, Some("3".to_owned()));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    std::fs::write(
+        temp_dir.path().join("00000.log"),
+        "v3{\"Insertion\":{\"key\":\"a\",\"value\":\"1\"}}\n",
+    )?;
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("a record of a newer schema is refused");
+    assert!(format!("{:#}", err).contains("schema version 3"));
+    Ok(())
+}
+
+// A record past `max_record_len` fails the open, naming where it is, instead of being read.
+#[test]
+fn oversized_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    let mut log = OLD_SCHEMA_LOG.as_bytes().to_vec();
+    let offset = log.len();
+    log.extend(std::iter::repeat(b'x').take(64 * 1024));
+    std::fs::write(temp_dir.path().join("00000.log"), &log)?;
+
+    let config = KvStoreConfig {
+        max_record_len: Some(1024),
+        ..Default::default()
+    };
+    let err = KvStore::open_with_config(temp_dir.path(), config)
+        .err()
+        .expect("an oversized record is refused");
+    assert!(format!("{:#}", err).contains(&format!(
+        "Record in log file 0 at offset {} is longer than 1024 bytes.",
+        offset
+    )));
+    assert_eq!(std::fs::read(temp_dir.path().join("00000.log"))?, log);
+    Ok(())
+}
+
+fn set_owned_pairs(engine: impl KvsEngine) -> Result<()> {
+    for i in 0..10 {
+        engine.set_owned(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.set_owned("key1".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value".to_owned()));
+    assert_eq!(engine.get("key9")?, Some("value9".to_owned()));
+    Ok(())
+}
+
+// Owned pairs are stored as borrowed ones, also with the filters keeping copies of keys.
+#[test]
+fn set_owned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_owned_pairs(KvStore::open(temp_dir.path().join("kvs"))?)?;
+    let config = KvStoreConfig {
+        bloom_filter: true,
+        capacity: Some(Capacity::Keys(5)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path().join("cache"), config)?;
+    set_owned_pairs(store.clone())?;
+    assert_eq!(store.count_prefix("key")?, 5);
+    set_owned_pairs(SledAdapter::open(temp_dir.path().join("sled"))?)?;
+    set_owned_pairs(ShardedKvStore::open(
+        (0..2).map(|i| temp_dir.path().join(format!("shard{}", i))),
+    )?)
+}
+
+// The key read far more than the others, here by `try_get`, tops the hot keys, even
+// counting one read in 4 at random.
+#[test]
+fn hot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value")?;
+    store.get("key1")?;
+    assert!(store.hot_keys(10)?.is_empty());
+    drop(store);
+
+    let config = KvStoreConfig {
+        sample_reads: Some(4),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..100 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    for round in 0..10 {
+        for i in 0..100 {
+            store.get(&format!("key{}", i))?;
+            if i % 10 == round {
+                for _ in 0..20 {
+                    store.try_get("key42")?;
+                }
+            }
+        }
+    }
+    store.get("missing")?;
+    let hot = store.hot_keys(3)?;
+    assert_eq!(hot.len(), 3);
+    assert_eq!(hot[0].0, "key42");
+    assert!(hot[0].1 >= 100 && hot[0].1 > hot[1].1 * 3);
+
+    // Keys read in bulk count as well.
+    store.get_many(&["key7"; 8000])?;
+    assert_eq!(store.hot_keys(1)?[0].0, "key7");
+    Ok(())
+}
+
+// A record rewritten under the index is caught on a verified open, naming its key.
+#[test]
+fn verify_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    store.compact()?;
+    let (file_id, offset, _) = store.dump_commands()?[1].clone();
+    drop(store);
+    let config = || KvStoreConfig {
+        verify_on_open: true,
+        ..Default::default()
+    };
+    drop(KvStore::open_with_config(temp_dir.path(), config())?);
+
+    let path = temp_dir.path().join(format!("{:05}.log", file_id));
+    let mut log = std::fs::read(&path)?;
+    let record = &mut log[offset as usize..];
+    let at = record.windows(4).position(|w| w == b"key2").unwrap();
+    record[at..at + 4].copy_from_slice(b"keyX");
+    std::fs::write(&path, &log)?;
+
+    let err = KvStore::open_with_config(temp_dir.path(), config())
+        .err()
+        .expect("a corrupt index is caught");
+    assert!(format!("{:#}", err).contains(&format!(
+        "Index entry of key key2 points at no insertion of it, log file {} offset {}",
+        file_id, offset
+    )));
+    // Without verification the open goes through, the read fails.
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get("key2").is_err());
+
+    // A value cut off the value log.
+    let dir = temp_dir.path().join("value_log");
+    let store = KvStore::open_with_config(
+        &dir,
+        KvStoreConfig {
+            value_log_threshold: Some(16),
+            ..Default::default()
+        },
+    )?;
+    store.set("key1", &"v".repeat(64))?;
+    store.close()?;
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension() == Some("vlog".as_ref()) {
+            let len = std::fs::metadata(&path)?.len();
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(len - 1)?;
+        }
+    }
+    let err = KvStore::open_with_config(&dir, config())
+        .err()
+        .expect("a missing value is caught");
+    assert!(format!("{:#}", err).contains("Value of key key1 is missing from the value log"));
+    Ok(())
+}
+
+// A log file failing to read is reported, the other files are still checked.
+// `/proc/self/mem` is a file whose first page can't be read.
+#[cfg(target_os = "linux")]
+#[test]
+fn verify_unreadable_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.close()?;
+    let log = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .max()
+        .unwrap();
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(b"garbage\n")?;
+    std::os::unix::fs::symlink("/proc/self/mem", temp_dir.path().join("99999.log"))?;
+
+    let issues = KvStore::verify(temp_dir.path())?;
+    assert!(issues
+        .iter()
+        .any(|issue| matches!(issue, IntegrityIssue::UnreadableFile { file_id: 99999, .. })));
+    assert!(issues
+        .iter()
+        .any(|issue| matches!(issue, IntegrityIssue::CorruptRecord { .. })));
+    Ok(())
+}
+
+// The writes after a log cursor come back in log order, resumable from any of them.
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    let (file_id, offset) = store.log_position()?;
+    store.set("key3", "value3")?;
+    store.remove("key1")?;
+    store.set("key2", "value4")?;
+
+    let changes = store
+        .changes_since(file_id, offset)?
+        .collect::<Result<Vec<_>>>()?;
+    let writes: Vec<_> = changes
+        .iter()
+        .map(|change| (change.key.as_str(), change.value.as_deref()))
+        .collect();
+    assert_eq!(
+        writes,
+        vec![
+            ("key3", Some("value3")),
+            ("key1", None),
+            ("key2", Some("value4"))
+        ]
+    );
+
+    // Resume after the first change.
+    let first = &changes[0];
+    let rest = store.changes_since(first.file_id, first.next_offset)?;
+    assert_eq!(rest.collect::<Result<Vec<_>>>()?, changes[1..].to_vec());
+    let last = changes.last().unwrap();
+    assert_eq!(
+        store.changes_since(last.file_id, last.next_offset)?.count(),
+        0
+    );
+    Ok(())
+}
+
+// A value failing to read ends the changes with its error instead of cutting them short.
+#[test]
+fn changes_since_unreadable_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        value_log_threshold: Some(16),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1", "value1")?;
+    store.set("key2", &"v".repeat(64))?;
+    store.set("key3", "value3")?;
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("vlog".as_ref()) {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(0)?;
+        }
+    }
+
+    let changes: Vec<_> = store.changes_since(0, 0)?.collect();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].as_ref().unwrap().key, "key1");
+    assert!(changes[1].is_err());
+    Ok(())
+}
+
+// Writes older than `fsync_interval` are on disk even if the store is never flushed.
+#[test]
+fn fsync_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_millis(50);
+    let store = KvStore::open_with_config(
+        temp_dir.path(),
+        KvStoreConfig {
+            fsync_interval: Some(interval),
+            ..Default::default()
+        },
+    )?;
+    for i in 0..100 {
+        store.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    thread::sleep(interval * 3);
+    // Simulate a crash, skipping every flush on drop.
+    std::mem::forget(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(
+            store.get(&format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// Setting a key to its current value appends nothing with `dedup_writes`.
+#[test]
+fn dedup_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(
+        temp_dir.path(),
+        KvStoreConfig {
+            dedup_writes: true,
+            ..Default::default()
+        },
+    )?;
+    for _ in 0..100 {
+        store.set("key1", "value1")?;
+    }
+    assert_eq!(store.dump_commands()?.len(), 1);
+
+    store.set("key1", "value2")?;
+    assert_eq!(store.dump_commands()?.len(), 2);
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Comparing with the current value is no read the access hook vetoes, and a write found
+// to change nothing fits in a full quota.
+#[test]
+fn dedup_writes_without_reading() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(
+        temp_dir.path(),
+        KvStoreConfig {
+            dedup_writes: true,
+            max_disk_bytes: Some(4096),
+            compaction_threshold: Some(usize::MAX),
+            ..Default::default()
+        },
+    )?;
+    store.set_access_hook(Some(Box::new(DenyReadOnly)))?;
+    store.set("secret:key", "value1")?;
+    store.set("secret:key", "value1")?;
+    assert_eq!(store.dump_commands()?.len(), 1);
+
+    let mut written = 0;
+    let err = loop {
+        match store.set(&format!("key{}", written), "value") {
+            Ok(()) => written += 1,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err.downcast_ref(), Some(&KvError::DiskQuotaExceeded));
+    store.set("key0", "value")?;
+    Ok(())
+}
+
+#[test]
+fn get_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_meta("key1")?, None);
+
+    store.set("key1", "value1")?;
+    let first = store.get_meta("key1")?.expect("key1 is missing");
+    assert_eq!(first.value_len, 6);
+    thread::sleep(Duration::from_millis(5));
+    store.set("key1", "value12")?;
+    let second = store.get_meta("key1")?.expect("key1 is missing");
+    assert_eq!(second.value_len, 7);
+    assert!(second.last_modified.unwrap() > first.last_modified.unwrap());
+    Ok(())
+}
+
+// The metadata of a value in the value log comes from its pointer, the value isn't read.
+#[test]
+fn get_meta_without_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        value_log_threshold: Some(1024),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("big", &"v".repeat(4096))?;
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("vlog".as_ref()) {
+            std::fs::remove_file(path)?;
+        }
+    }
+    assert!(store.get("big").is_err());
+    let meta = store.get_meta("big")?.expect("big is missing");
+    assert_eq!(meta.value_len, 4096);
+    assert!(meta.last_modified.is_some());
+    Ok(())
+}
+
+/// Stands still until moved forward by the test.
+#[derive(Debug, Default)]
+struct ManualClock(AtomicU64);
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Writes are stamped with the time of the given clock, moved forward without sleeping.
+#[test]
+fn manual_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let store = KvStore::builder()
+        .clock(clock.clone())
+        .open(temp_dir.path())?;
+    clock.0.store(1_000, Ordering::SeqCst);
+    store.set("old", "value")?;
+    clock.0.store(60_000, Ordering::SeqCst);
+    store.set("new", "value")?;
+
+    assert_eq!(store.get_meta("old")?.unwrap().last_modified, Some(1_000));
+    assert_eq!(store.get_meta("new")?.unwrap().last_modified, Some(60_000));
+    let since = UNIX_EPOCH + Duration::from_millis(60_000);
+    assert_eq!(store.keys_modified_since(since)?, vec!["new"]);
+    Ok(())
+}
+
+// Only the keys written inside the window are listed, before and after reopening.
+#[test]
+fn keys_modified_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("old1", "value")?;
+    store.set("old2", "value")?;
+    thread::sleep(Duration::from_millis(5));
+    let since = SystemTime::now();
+    thread::sleep(Duration::from_millis(5));
+    store.set("new1", "value")?;
+    store.set("old2", "value2")?;
+    store.set("gone", "value")?;
+    store.remove("gone")?;
+    assert_eq!(store.keys_modified_since(since)?, vec!["new1", "old2"]);
+    assert_eq!(store.keys_modified_since(UNIX_EPOCH)?.len(), 3);
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys_modified_since(since)?, vec!["new1", "old2"]);
+    Ok(())
+}
+
+// Large values go to the value log and survive compaction, garbage collection and reopening.
+#[test]
+fn value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        value_log_threshold: Some(1024),
+        ..Default::default()
+    };
+    let big = |round: usize| format!("{}", round).repeat(4096);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let rewritten = Arc::new(Mutex::new(0));
+    let rewritten_ref = rewritten.clone();
+    store.on_compaction(Box::new(move |stats| {
+        *rewritten_ref.lock().unwrap() = stats.bytes_after
+    }))?;
+    for round in 0..3 {
+        for i in 0..10 {
+            store.set(&format!("big{}", i), &big(round))?;
+        }
+    }
+    store.set("small", "value")?;
+    store.remove("big9")?;
+
+    store.compact()?;
+    // Only pointers were copied, not the 4KB values.
+    assert!(*rewritten.lock().unwrap() < 10 * 1024);
+    assert_eq!(store.get("big0")?, Some(big(2)));
+    assert_eq!(store.get_meta("big0")?.unwrap().value_len, 4096);
+
+    assert!(store.collect_value_garbage()? >= 20 * 4096);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..9 {
+        assert_eq!(store.get(&format!("big{}", i))?, Some(big(2)));
+    }
+    assert_eq!(store.get("big9")?, None);
+    assert_eq!(store.get("small")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Defragmenting lays out the records and the values of the value log in key order,
+// whatever order the keys were written in.
+#[test]
+fn defragment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        value_log_threshold: Some(16),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let key = |i: usize| format!("key{:03}", i);
+    let value = |i: usize| format!("<value of {}>", key(i));
+    for i in 0..100 {
+        let shuffled = i * 37 % 100;
+        store.set(&key(shuffled), &value(shuffled))?;
+        store.set(&format!("small{}", shuffled), "v")?;
+    }
+    store.defragment()?;
+
+    let keys: Vec<_> = store
+        .dump_commands()?
+        .into_iter()
+        .map(|(_, _, summary)| match summary {
+            CommandSummary::Insertion { key, .. } => key,
+            summary => panic!("Unexpected record: {:?}", summary),
+        })
+        .collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys.len(), 200);
+    assert_eq!(keys, sorted);
+
+    let value_logs: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("vlog".as_ref()))
+        .collect();
+    assert_eq!(value_logs.len(), 1);
+    let values = std::fs::read_to_string(value_logs[0].path())?;
+    let offsets: Vec<_> = (0..100).map(|i| values.find(&value(i)).unwrap()).collect();
+    assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+    for i in 0..100 {
+        assert_eq!(store.get(&key(i))?, Some(value(i)));
+    }
+    Ok(())
+}
+
+// Values past `chunk_size` are split into bounded records, whole again on every read path.
+#[test]
+fn chunked_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        chunk_size: Some(1000),
+        ..Default::default()
+    };
+    // Multi-byte characters don't fall on chunk boundaries.
+    let big = |round: usize| format!("é{}", round).repeat(20_000);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("big", &big(0))?;
+    store.set("small", "value")?;
+    assert_eq!(store.get("big")?, Some(big(0)));
+    assert_eq!(store.get_meta("big")?.unwrap().value_len, big(0).len());
+    let chunks: Vec<_> = store
+        .dump_commands()?
+        .into_iter()
+        .filter_map(|(_, _, summary)| match summary {
+            CommandSummary::Chunk { len, .. } => Some(len),
+            _ => None,
+        })
+        .collect();
+    assert!(chunks.len() >= 60);
+    assert!(chunks.iter().all(|&len| len <= 1000));
+    let (file_id, _) = store.log_position()?;
+    assert!(store
+        .changes_since(file_id, 0)?
+        .any(|change| change.unwrap().value == Some(big(0))));
+
+    store.set("big", &big(1))?;
+    store.compact()?;
+    assert_eq!(store.get("big")?, Some(big(1)));
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("big")?, Some(big(1)));
+    assert_eq!(store.get("small")?, Some("value".to_owned()));
+    let segments = store.segments()?;
+    assert_eq!(
+        segments.iter().map(|s| s.live_records).sum::<usize>(),
+        chunks.len() + 2
+    );
+    Ok(())
+}
+
+// Keys spill into the on-disk index past a tiny threshold, reads stay correct.
+#[test]
+fn spilled_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        index_spill_threshold: Some(4),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..300 {
+        store.set(&format!("key{:03}", i), &format!("value{}", i))?;
+    }
+    for i in (0..300).step_by(3) {
+        store.remove(&format!("key{:03}", i))?;
+    }
+    for i in (1..300).step_by(3) {
+        store.set(&format!("key{:03}", i), "overwritten")?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..300 {
+            let expected = match i % 3 {
+                0 => None,
+                1 => Some("overwritten".to_owned()),
+                _ => Some(format!("value{}", i)),
+            };
+            assert_eq!(store.get(&format!("key{:03}", i))?, expected);
+        }
+        assert_eq!(store.count_prefix("key")?, 200);
+        assert_eq!(store.first_key()?, Some("key001".to_owned()));
+        assert_eq!(store.last_key()?, Some("key299".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    assert!(store.remove("key000").is_err());
+    drop(store);
+
+    check(&KvStore::open_with_config(temp_dir.path(), config)?)
+}
+
+// Spills merge into a few runs of growing size, a corrupted run fails reads instead of
+// cutting them short.
+#[test]
+fn spilled_index_runs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        index_spill_threshold: Some(4),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..2000 {
+        store.set(&format!("key{:04}", i), "value")?;
+    }
+    let runs: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<_>>()?;
+    let runs: Vec<_> = runs
+        .into_iter()
+        .filter(|path| path.to_string_lossy().contains(".index."))
+        .collect();
+    assert!(!runs.is_empty() && runs.len() <= 12, "{} runs", runs.len());
+    assert_eq!(store.count_prefix("key")?, 2000);
+
+    for run in &runs {
+        std::fs::write(run, "not an index entry\n")?;
+    }
+    assert!(store.count_prefix("key").is_err());
+    assert!(store.scan_after(None, 3000).is_err());
+    Ok(())
+}
+
+// `compact_if_needed` only compacts past the threshold.
+#[test]
+fn compact_if_needed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(!store.compact_if_needed()?);
+    for i in 0..100 {
+        store.set("key1", &i.to_string())?;
+    }
+    assert!(!store.compact_if_needed()?);
+    drop(store);
+
+    // Lowered on reopen, so no write schedules a compaction before the call.
+    let config = KvStoreConfig {
+        compaction_threshold: Some(10),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(store.compact_if_needed()?);
+    assert!(!store.compact_if_needed()?);
+    assert_eq!(store.get("key1")?, Some("99".to_owned()));
+    Ok(())
+}
+
+// The compaction hook receives what the compaction did.
+#[test]
+fn compaction_hook() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let fired = Arc::new(Mutex::new(Vec::new()));
+    let fired_ref = fired.clone();
+    store.on_compaction(Box::new(move |stats| fired_ref.lock().unwrap().push(stats)))?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(&format!("key{}", key_id), &format!("{}", iter))?;
+        }
+    }
+    store.compact()?;
+    // Joins the compactions scheduled by the writes.
+    drop(store);
+
+    let fired = fired.lock().unwrap();
+    assert!(!fired.is_empty());
+    for stats in fired.iter() {
+        assert!(stats.records <= 100);
+        assert!(stats.bytes_after <= stats.bytes_before);
+    }
+    assert_eq!(fired.last().unwrap().records, 100);
+    assert!(fired
+        .iter()
+        .any(|stats| stats.bytes_after < stats.bytes_before));
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -218,3 +1609,538 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Past its capacity the store evicts the least recently used keys, or the least often used ones.
+#[test]
+fn eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        capacity: Some(Capacity::Keys(3)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for key in ["a", "b", "c"] {
+        store.set(key, key)?;
+    }
+    store.get("a")?;
+    store.set("d", "d")?;
+    assert_eq!(store.get("b")?, None);
+    store.set("e", "e")?;
+    assert_eq!(store.get("c")?, None);
+    for key in ["a", "d", "e"] {
+        assert_eq!(store.get(key)?, Some(key.to_owned()));
+    }
+    drop(store);
+
+    // Reopened with a smaller capacity, the oldest writes go first.
+    let config = KvStoreConfig {
+        capacity: Some(Capacity::Keys(2)),
+        ..config
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("a")?, None);
+    assert_eq!(store.get("d")?, Some("d".to_owned()));
+    assert_eq!(store.get("e")?, Some("e".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        capacity: Some(Capacity::Bytes(20)),
+        eviction_policy: EvictionPolicy::Lfu,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("hot", "12345")?;
+    store.set("cold", "12345")?;
+    store.get("hot")?;
+    store.get("hot")?;
+    store.get("cold")?;
+    store.set("new", "12345")?;
+    assert_eq!(store.get("cold")?, None);
+    assert_eq!(store.get("hot")?, Some("12345".to_owned()));
+    assert_eq!(store.get("new")?, Some("12345".to_owned()));
+    Ok(())
+}
+
+// A touched key counts as used like a read one, without growing the log.
+#[test]
+fn touch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        capacity: Some(Capacity::Keys(2)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("a", "a")?;
+    store.set("b", "b")?;
+    let position = store.log_position()?;
+    assert!(store.touch("a")?);
+    assert!(!store.touch("missing")?);
+    assert_eq!(store.log_position()?, position);
+    store.set("c", "c")?;
+    assert_eq!(store.get("a")?, Some("a".to_owned()));
+    assert_eq!(store.get("b")?, None);
+    Ok(())
+}
+
+// A read-only open never writes, so it works on a directory the process can't write to.
+#[cfg(unix)]
+#[test]
+fn read_only() -> Result<()> {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("store");
+    let store = KvStore::open(&dir)?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.close()?;
+    let set_mode = |file_mode, dir_mode| -> Result<()> {
+        for entry in fs::read_dir(&dir)? {
+            fs::set_permissions(entry?.path(), Permissions::from_mode(file_mode))?;
+        }
+        fs::set_permissions(&dir, Permissions::from_mode(dir_mode))?;
+        Ok(())
+    };
+    set_mode(0o444, 0o555)?;
+    let snapshot = || -> Result<Vec<_>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            entries.push((path.clone(), fs::read(path)?));
+        }
+        entries.sort();
+        Ok(entries)
+    };
+    let before = snapshot()?;
+
+    let store = KvStore::open_read_only(&dir)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    let err = store.set("key3", "value3").unwrap_err();
+    assert_eq!(err.to_string(), "The store is opened read-only.");
+    assert!(store.remove("key1").is_err());
+    assert!(store.compact().is_err());
+    drop(store);
+    assert_eq!(snapshot()?, before);
+
+    // Root ignores the permissions.
+    if fs::write(dir.join("probe"), "").is_err() {
+        let err = KvStore::open(&dir).err().expect("opened for writes");
+        assert!(
+            err.chain().any(|cause| matches!(
+                cause.downcast_ref::<std::io::Error>(),
+                Some(e) if e.kind() == std::io::ErrorKind::PermissionDenied
+            )),
+            "{:#}",
+            err
+        );
+    }
+    set_mode(0o644, 0o755)
+}
+
+// Repeated reads share the cached value, writes replace it.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        value_cache_size: Some(64),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1", "value1")?;
+    let first = store.get_shared("key1")?.unwrap();
+    assert!(Arc::ptr_eq(&first, &store.get_shared("key1")?.unwrap()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    store.set("key1", "value2")?;
+    assert_eq!(store.get_shared("key1")?.as_deref(), Some("value2"));
+    store.remove("key1")?;
+    assert_eq!(store.get_shared("key1")?, None);
+
+    // Past the capacity the least recently read values are dropped, not the keys.
+    let value = "v".repeat(40);
+    store.set("key2", &value)?;
+    store.set("key3", &value)?;
+    let cached = store.get_shared("key2")?.unwrap();
+    store.get_shared("key3")?;
+    assert!(!Arc::ptr_eq(&cached, &store.get_shared("key2")?.unwrap()));
+    assert_eq!(store.get("key3")?, Some(value));
+
+    // A value larger than the cache is not cached, nor does it drop the cached ones.
+    let cached = store.get_shared("key2")?.unwrap();
+    store.set("key4", &"v".repeat(100))?;
+    let large = store.get_shared("key4")?.unwrap();
+    assert!(!Arc::ptr_eq(&large, &store.get_shared("key4")?.unwrap()));
+    assert!(Arc::ptr_eq(&cached, &store.get_shared("key2")?.unwrap()));
+    Ok(())
+}
+
+// Overwrites leave dead records in the older log file, the newer one holds the live ones.
+#[test]
+fn segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..10 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    store.compact()?;
+    for i in 0..4 {
+        store.set(&format!("key{}", i), "new")?;
+    }
+    store.remove("key9")?;
+
+    let segments = store.segments()?;
+    let (older, active) = (&segments[0], segments.last().unwrap());
+    assert_eq!((older.live_records, older.dead_records), (5, 5));
+    assert_eq!((active.live_records, active.dead_records), (4, 1));
+    assert_eq!(
+        active.size_bytes,
+        std::fs::metadata(temp_dir.path().join(format!("{:05}.log", active.file_id)))?.len()
+    );
+    Ok(())
+}
+
+// Overwritten records are dead weight in the log files until a compaction drops them.
+#[test]
+fn fragmentation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..10 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    let fresh = store.stats()?;
+    assert_eq!(fresh.live_bytes, fresh.disk_bytes);
+    assert_eq!(fresh.fragmentation, 0.0);
+
+    for i in 0..10 {
+        store.set(&format!("key{}", i), "other")?;
+    }
+    let overwritten = store.stats()?;
+    assert_eq!(overwritten.live_bytes, store.live_bytes()?);
+    assert_eq!(overwritten.live_bytes, fresh.live_bytes);
+    assert!((overwritten.fragmentation - 0.5).abs() < 0.01);
+
+    store.compact()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.live_bytes, fresh.live_bytes);
+    assert_eq!(compacted.fragmentation, 0.0);
+    Ok(())
+}
+
+// The estimate counts the garbage written, and the bytes the compaction then frees.
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..10 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    for i in 0..3 {
+        store.set(&format!("key{}", i), "new")?;
+    }
+    store.remove("key9")?;
+
+    let estimate = store.compaction_estimate()?;
+    assert_eq!((estimate.live_records, estimate.dead_records), (9, 5));
+    let log_size = |store: &KvStore| -> Result<u64> {
+        Ok(store.segments()?.iter().map(|s| s.size_bytes).sum())
+    };
+    let before = log_size(&store)?;
+    store.compact()?;
+    assert_eq!(
+        before - log_size(&store)?,
+        estimate.estimated_bytes_reclaimed
+    );
+    let estimate = store.compaction_estimate()?;
+    assert_eq!((estimate.live_records, estimate.dead_records), (9, 0));
+    assert_eq!(estimate.estimated_bytes_reclaimed, 0);
+    Ok(())
+}
+
+// Options set through the builder take effect.
+#[test]
+fn builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .data_subdir("data")
+        .max_file_size(256)
+        .compaction_threshold(1000)
+        .cache_capacity(1024)
+        .durability(Durability::Periodic(Duration::from_millis(10)))
+        .open(temp_dir.path())?;
+    // More garbage than the default threshold allows.
+    for i in 0..100 {
+        store.set(&format!("key{}", i), "value")?;
+        store.set(&format!("key{}", i), "value")?;
+    }
+    assert!(store.segments()?.len() > 5);
+    let dead_records: usize = store.segments()?.iter().map(|s| s.dead_records).sum();
+    assert_eq!(dead_records, 100);
+    store.close()?;
+    assert!(temp_dir.path().join("data").join("00000.log").exists());
+
+    let store = KvStore::builder()
+        .data_subdir("data")
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key99")?, Some("value".to_owned()));
+    assert!(store.set("key1", "value").is_err());
+    Ok(())
+}
+
+// Overwrites fit in a small quota thanks to compaction, new keys eventually don't.
+#[test]
+fn disk_quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_disk_bytes: Some(4096),
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..1000 {
+        store.set("key", &format!("value{}", i))?;
+    }
+    let mut written = 0;
+    let err = loop {
+        match store.set(&format!("key{}", written), "value") {
+            Ok(()) => written += 1,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err.downcast_ref(), Some(&KvError::DiskQuotaExceeded));
+    assert!(written > 10);
+    assert_eq!(store.get("key")?, Some("value999".to_owned()));
+    assert_eq!(store.get("key0")?, Some("value".to_owned()));
+    store.remove("key0")?;
+    Ok(())
+}
+
+// Batches compact once to fit in the quota like single sets, and one that doesn't fit
+// writes nothing.
+#[test]
+fn disk_quota_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_disk_bytes: Some(4096),
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..1000 {
+        store.set("key", &format!("value{}", i))?;
+    }
+    let batch = |prefix: &str, len: usize| {
+        (0..len)
+            .map(|i| (format!("{}{}", prefix, i), "value".to_owned()))
+            .collect::<Vec<_>>()
+    };
+    store.set_batch(batch("small", 20))?;
+    assert_eq!(store.get("small19")?, Some("value".to_owned()));
+
+    let err = store.set_batch(batch("large", 100)).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvError::DiskQuotaExceeded));
+    assert_eq!(store.get("large0")?, None);
+    let mut log_bytes = 0;
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            log_bytes += std::fs::metadata(&path)?.len();
+        }
+    }
+    assert!(log_bytes <= 4096, "{} bytes", log_bytes);
+    Ok(())
+}
+
+// The write position moves forward with each set, onto a larger file id on rollover.
+#[test]
+fn write_position() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_file_size(256)
+        .open(temp_dir.path())?;
+    let mut position = store.write_position()?;
+    let mut rollovers = 0;
+    for i in 0..100 {
+        store.set(&format!("key{}", i), "value")?;
+        let next = store.write_position()?;
+        assert!(next > position);
+        if next.0 != position.0 {
+            rollovers += 1;
+        }
+        position = next;
+    }
+    assert!(rollovers > 1);
+    Ok(())
+}
+
+fn remove_batch_on(store: impl KvsEngine) -> Result<()> {
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    let removed = store.remove_batch(&["key1", "missing", "key3", "key1"])?;
+    assert_eq!(removed, vec![true, false, true, false]);
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    Ok(())
+}
+
+#[test]
+fn remove_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_batch_on(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn remove_batch_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_batch_on(SledAdapter::open(temp_dir.path())?)
+}
+
+// The engine of a directory is told by its mark, or else by the files each engine leaves.
+#[test]
+fn detect_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (kvs_dir, sled_dir) = (temp_dir.path().join("kvs"), temp_dir.path().join("sled"));
+    assert_eq!(detect_engine(&kvs_dir)?, None);
+
+    KvStore::open(&kvs_dir)?.set("key1", "value1")?;
+    assert_eq!(detect_engine(&kvs_dir)?, Some(EngineType::Kvs));
+    let sled = SledAdapter::open(&sled_dir)?;
+    sled.set("key1", "value1")?;
+    sled.close()?;
+    assert_eq!(detect_engine(&sled_dir)?, Some(EngineType::Sled));
+
+    let empty_dir = temp_dir.path().join("empty");
+    std::fs::create_dir(&empty_dir)?;
+    std::fs::write(empty_dir.join(ENGINE_MARK_FILE), "")?;
+    assert_eq!(detect_engine(&empty_dir)?, None);
+    std::fs::write(empty_dir.join(ENGINE_MARK_FILE), "sled")?;
+    assert_eq!(detect_engine(&empty_dir)?, Some(EngineType::Sled));
+    std::fs::write(empty_dir.join(ENGINE_MARK_FILE), "rocks")?;
+    assert!(detect_engine(&empty_dir).is_err());
+    Ok(())
+}
+
+// Closing flushes the writes even if only asynchronous flushes were asked for.
+#[test]
+fn close_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledAdapter::open(temp_dir.path())?.with_async_flush(true);
+    store.set("key1", "value1")?;
+    store.flush_async()?;
+    store.set("key2", "value2")?;
+    store.close()?;
+
+    let store = SledAdapter::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+struct DenyReadOnly;
+
+impl AccessHook for DenyReadOnly {
+    fn before_get(&self, key: &str) -> Result<()> {
+        if key.starts_with("secret:") {
+            anyhow::bail!("{} is secret", key);
+        }
+        Ok(())
+    }
+    fn before_set(&self, key: &str) -> Result<()> {
+        if key.starts_with("readonly:") {
+            anyhow::bail!("{} is read-only", key);
+        }
+        Ok(())
+    }
+    fn before_remove(&self, key: &str) -> Result<()> {
+        self.before_set(key)
+    }
+}
+
+// Accesses vetoed by the access hook fail with its error and leave the store as it was.
+#[test]
+fn access_hook() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("readonly:key", "value1")?;
+    store.set_access_hook(Some(Box::new(DenyReadOnly)))?;
+
+    let err = store.set("readonly:key", "value2").unwrap_err();
+    assert_eq!(err.to_string(), "readonly:key is read-only");
+    let ops = vec![
+        WriteOp::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        WriteOp::Set {
+            key: "readonly:key".to_owned(),
+            value: "value2".to_owned(),
+        },
+    ];
+    assert!(store.transaction(ops).is_err());
+    store.set("key1", "value1")?;
+    assert_eq!(store.get("readonly:key")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    // Removals of several keys write none of them unless all are allowed.
+    assert!(store.remove_batch(&["key1", "readonly:key"]).is_err());
+    assert!(store.remove_prefix("").is_err());
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    // Every read path asks the hook.
+    store.set("secret:key", "value")?;
+    assert!(store.get("secret:key").is_err());
+    assert!(store.value_len("secret:key").is_err());
+    assert!(store.get_meta("secret:key").is_err());
+    assert!(store.scan_prefix("secret:").is_err());
+    assert!(store.scan_after(None, 10).is_err());
+    assert_eq!(store.scan_after(None, 1)?.len(), 1);
+
+    store.set_access_hook(None)?;
+    store.set("readonly:key", "value2")?;
+    assert_eq!(store.get("readonly:key")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Appends from several threads all land, none overwrites another.
+#[test]
+fn concurrent_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.append("log", &format!("{}:{};", t, i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let expected: usize = (0..8)
+        .flat_map(|t| (0..100).map(move |i| format!("{}:{};", t, i).len()))
+        .sum();
+    assert_eq!(store.get("log")?.unwrap().len(), expected);
+    Ok(())
+}
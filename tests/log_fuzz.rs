@@ -0,0 +1,159 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+use kvs::engine::KvStore;
+use kvs::{KvsEngine, Result};
+
+/// Inputs which used to panic.
+const REGRESSIONS: &[&[u8]] = &[
+    // Allocated the value length given by the pointer before reading it.
+    b"{\"Pointer\":{\"key\":\"a\",\"ptr\":{\"file_id\":0,\"offset\":0,\"len\":18446744073709551615}}}\n",
+    // Overflowed checking the bounds of the value.
+    b"{\"Pointer\":{\"key\":\"a\",\"ptr\":{\"file_id\":0,\"offset\":18446744073709551615,\"len\":1}}}\n",
+    // Not UTF-8.
+    b"{\"Insertion\":{\"key\":\"a\",\"value\":\"\xff\xfe\"}}\n",
+    // Torn write.
+    b"{\"Insertion\":{\"key\":\"a\",\"value\":\"1\"}}\n{\"Insertion\":{\"key\":\"b\",\"val",
+];
+
+/// Bytes inserted into valid logs, the ones meaningful to JSON and a non UTF-8 one.
+const JUNK: &[u8] = b"\"{}:,\n\xff0";
+
+/// Open a store whose only log file holds `log`, replayed from its start, and read it all.
+/// A value log is there for the pointers to point into.
+/// Anything may fail, nothing may panic.
+fn open_damaged(log: &[u8]) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path()).expect("unable to create the store"));
+    fs::write(temp_dir.path().join("00000.log"), log).expect("unable to write the log");
+    fs::write(temp_dir.path().join("00000.vlog"), "values").expect("unable to write the values");
+    let _ = KvStore::verify(temp_dir.path());
+    if let Ok(store) = KvStore::open(temp_dir.path()) {
+        let _ = exercise(&store);
+    }
+}
+
+fn exercise(store: &KvStore) -> Result<()> {
+    let _ = store.dump_commands();
+    let _ = store.changes_since(0, 0).map(|changes| changes.count());
+    for (key, _) in store.scan_after(None, usize::MAX)? {
+        store.get(&key)?;
+    }
+    store.get("a")?;
+    store.set("a", "after")?;
+    store.compact()
+}
+
+/// The log written by a few real writes.
+fn valid_log() -> Vec<u8> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("unable to create the store");
+    for i in 0..8 {
+        store.set(&format!("key{}", i), &i.to_string()).unwrap();
+    }
+    store.remove("key3").unwrap();
+    drop(store);
+    read_log(temp_dir.path())
+}
+
+fn read_log(dir: &Path) -> Vec<u8> {
+    fs::read(dir.join("00000.log")).expect("unable to read the log")
+}
+
+#[test]
+fn regressions() {
+    for log in REGRESSIONS {
+        open_damaged(log);
+    }
+}
+
+// Random bytes, and valid logs with random damage, never make the store panic.
+#[test]
+fn random_logs() {
+    let mut rng = StdRng::seed_from_u64(0x6b76_7331);
+    let valid = valid_log();
+    for _ in 0..100 {
+        let len = rng.gen_range(0..256);
+        open_damaged(&(0..len).map(|_| rng.gen()).collect::<Vec<u8>>());
+    }
+    for _ in 0..200 {
+        let mut log = valid.clone();
+        for _ in 0..rng.gen_range(1..4) {
+            let at = rng.gen_range(0..log.len());
+            match rng.gen_range(0..3) {
+                0 => log[at] = rng.gen(),
+                1 => log.truncate(at),
+                _ => log.insert(at, JUNK[rng.gen_range(0..JUNK.len())]),
+            }
+            if log.is_empty() {
+                break;
+            }
+        }
+        open_damaged(&log);
+    }
+}
+
+// A torn record at the end of the log is dropped, the next writes don't land on it.
+#[test]
+fn torn_record_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    fs::write(
+        temp_dir.path().join("00000.log"),
+        b"{\"Insertion\":{\"key\":\"a\",\"value\":\"1\"}}\n{\"Insertion\":{\"key\":\"b\",\"val",
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("b")?, None);
+    store.set("b", "2")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a")?, Some("1".to_owned()));
+    assert_eq!(store.get("b")?, Some("2".to_owned()));
+    Ok(())
+}
+
+// Without its saved index and with a corrupt record, a store is salvaged minus that record.
+#[test]
+fn salvage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=5 {
+        store.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    store.remove("key4")?;
+    let (file_id, offset, _) = store.dump_commands()?[1].clone();
+    store.close()?;
+
+    fs::remove_file(temp_dir.path().join(".dumpfile"))?;
+    let mut log = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join(format!("{:05}.log", file_id)))?;
+    log.seek(SeekFrom::Start(offset + 2))?;
+    log.write_all(b"garbage")?;
+    drop(log);
+
+    let (store, report) = KvStore::open_salvage(temp_dir.path())?;
+    assert_eq!(report.records, 5);
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key4")?, None);
+    for i in [1, 3, 5] {
+        assert_eq!(
+            store.get(&format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    store.set("key6", "value6")?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key6")?, Some("value6".to_owned()));
+    Ok(())
+}
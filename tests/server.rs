@@ -0,0 +1,954 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+
+use kvs::engine::{KvStore, KvStoreConfig, WriteOp};
+use kvs::protocol::{
+    read_message, write_message, write_message_compressed, Framing, LENGTH_PREFIX, MAX_MESSAGE_LEN,
+};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ClusterKvClient, FlushPolicy, Instruction, KvClient, KvError, KvReplica, KvServer, KvsEngine,
+    Response, Result, ServerHandle,
+};
+
+fn start_server(
+    server: KvServer<KvStore, SharedQueueThreadPool>,
+) -> Result<(ServerHandle, JoinHandle<Result<()>>)> {
+    let handle = server.handle()?;
+    Ok((handle, thread::spawn(move || server.run())))
+}
+
+fn new_server(temp_dir: &TempDir, addr: &str) -> Result<KvServer<KvStore, SharedQueueThreadPool>> {
+    KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        addr,
+    )
+}
+
+#[test]
+fn auth_with_correct_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4101";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?.with_auth_token("secret"))?;
+
+    let mut client = KvClient::connect_with_token(addr, "secret")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value1"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+#[test]
+fn auth_with_wrong_or_missing_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4102";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?.with_auth_token("secret"))?;
+
+    assert!(KvClient::connect_with_token(addr, "guess").is_err());
+
+    let mut client = KvClient::connect(addr)?;
+    let err = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("Authentication required"));
+    // The connection is closed after rejecting.
+    assert!(client.get("key1".to_owned()).is_err());
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
+#[test]
+fn auth_not_required() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4103";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvClient::connect_with_token(addr, "anything")?;
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value1"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+#[test]
+fn idle_connection_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4104";
+    let (handle, server) =
+        start_server(new_server(&temp_dir, addr)?.with_idle_timeout(Duration::from_millis(200)))?;
+
+    let mut idle = TcpStream::connect(addr)?;
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // The server hangs up instead of leaving the read blocked.
+    assert_eq!(idle.read(&mut [0; 16])?, 0);
+    // Active connections are served as usual.
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value1"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// More connections than pool threads are all served, idle ones don't hold a worker.
+#[test]
+fn more_connections_than_workers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4105";
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+    let (handle, server) = start_server(server)?;
+
+    let mut clients = (0..8)
+        .map(|_| KvClient::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    for round in 0..3 {
+        for (i, client) in clients.iter_mut().enumerate() {
+            let key = format!("key{}", i);
+            client.set(key.clone(), format!("value{}", round))?;
+            assert_eq!(client.get(key)?, Some(format!("value{}", round)));
+        }
+    }
+
+    drop(clients);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// Shutdown closes the connections still open and waits for their readers.
+#[test]
+fn shutdown_closes_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4130";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let get = Instruction::Get {
+        key: "key1".to_owned(),
+    };
+    write_message(&mut writer, &get)?;
+    let reply: Option<Response> = read_message(&mut reader)?;
+    assert!(reply.is_some());
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    assert_eq!(reader.read(&mut [0; 16])?, 0);
+    Ok(())
+}
+
+/// `KvStore` counting its flushes.
+#[derive(Clone)]
+struct FlushCounter {
+    store: KvStore,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl KvsEngine for FlushCounter {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.store.set(key, value)
+    }
+    fn remove(&self, key: &str) -> Result<()> {
+        self.store.remove(key)
+    }
+    fn scan_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.store.scan_after(after, limit)
+    }
+    fn flush(&self) -> Result<()> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        self.store.flush()
+    }
+}
+
+// Reads never flush, the shutdown always flushes once.
+#[test]
+fn flush_policies() -> Result<()> {
+    let cases = [
+        (FlushPolicy::EveryWrite, "127.0.0.1:4106", 10 + 1),
+        (FlushPolicy::EveryN(4), "127.0.0.1:4107", 2 + 1),
+        (FlushPolicy::OnDisconnect, "127.0.0.1:4108", 1 + 1),
+    ];
+    for (policy, addr, expected) in cases.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = FlushCounter {
+            store: KvStore::open(temp_dir.path())?,
+            flushes: Default::default(),
+        };
+        let flushes = engine.flushes.clone();
+        let server = KvServer::new(engine, SharedQueueThreadPool::new(2)?, *addr)?
+            .with_flush_policy(*policy);
+        let handle = server.handle()?;
+        let server = thread::spawn(move || server.run());
+
+        let mut client = KvClient::connect(*addr)?;
+        for i in 0..10 {
+            client.set(format!("key{}", i), "value".to_owned())?;
+        }
+        client.get("key1".to_owned())?;
+        drop(client);
+        // Let the connection thread notice the disconnection.
+        thread::sleep(Duration::from_millis(200));
+
+        handle.shutdown();
+        server.join().unwrap()?;
+        assert_eq!(flushes.load(Ordering::SeqCst), *expected, "{:?}", policy);
+    }
+    Ok(())
+}
+
+fn wait_until(mut converged: impl FnMut() -> Result<bool>) -> Result<()> {
+    for _ in 0..50 {
+        if converged()? {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("replica did not converge");
+}
+
+// More pairs than a page are streamed back in key order.
+#[test]
+fn scan_all() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4110";
+    let expected: Vec<_> = (0..600)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in expected.iter().rev() {
+        store.set(key, value)?;
+    }
+    drop(store);
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(client.scan_all()?, expected);
+    // The connection stays usable.
+    client.set("key000".to_owned(), "new".to_owned())?;
+    assert_eq!(client.scan_all()?[0].1, "new");
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// A transaction with a failing op leaves the store untouched.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4111";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let set = |key: &str| WriteOp::Set {
+        key: key.to_owned(),
+        value: "new".to_owned(),
+    };
+    let remove = |key: &str| WriteOp::Remove {
+        key: key.to_owned(),
+    };
+    let err = client
+        .transaction(vec![set("key2"), remove("key1"), remove("missing")])
+        .unwrap_err();
+    assert!(err.to_string().contains("missing"));
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value1"));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    client.transaction(vec![set("key2"), remove("key1")])?;
+    assert_eq!(
+        client.scan_all()?,
+        vec![("key2".to_owned(), "new".to_owned())]
+    );
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// The counters follow the requests and are exported as Prometheus text.
+#[test]
+fn metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4112";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    let text = client.metrics()?;
+    assert!(text.contains("# TYPE kvs_operations_total counter\n"));
+    assert!(text.contains("kvs_operations_total{op=\"set\"} 0\n"));
+    assert!(text.contains("kvs_active_connections 1\n"));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(client.remove("missing".to_owned()).is_err());
+    let text = client.metrics()?;
+    assert!(text.contains("kvs_operations_total{op=\"set\"} 1\n"));
+    assert!(text.contains("kvs_operations_total{op=\"metrics\"} 2\n"));
+    assert!(text.contains("kvs_errors_total 1\n"));
+    assert!(text.contains("kvs_read_bytes_total 10\n"));
+    assert!(text.contains("kvs_written_bytes_total 10\n"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// The replica catches up, follows new writes, and resumes after the primary restarts.
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4109";
+    let (handle, server) = start_server(new_server(&primary_dir, addr)?)?;
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    let store = KvStore::open(replica_dir.path())?;
+    store.set("stale", "value")?;
+    let replica = KvReplica::follow(addr, store.clone())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    client.remove("key1".to_owned())?;
+    wait_until(|| Ok(store.get("key3")?.is_some() && store.get("key1")?.is_none()))?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("stale")?, None);
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()?;
+    let position = replica.position();
+    let (handle, server) = start_server(new_server(&primary_dir, addr)?)?;
+    let mut client = KvClient::connect(addr)?;
+    client.set("key4".to_owned(), "value4".to_owned())?;
+    wait_until(|| Ok(store.get("key4")?.is_some()))?;
+    assert!(replica.position() > position);
+    assert_eq!(store.count_prefix("key")?, 3);
+
+    drop(replica);
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+/// `KvStore` whose reads take `delay`.
+#[derive(Clone)]
+struct SlowGet {
+    store: KvStore,
+    delay: Duration,
+}
+
+impl KvsEngine for SlowGet {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        thread::sleep(self.delay);
+        self.store.get(key)
+    }
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.store.set(key, value)
+    }
+    fn remove(&self, key: &str) -> Result<()> {
+        self.store.remove(key)
+    }
+    fn scan_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.store.scan_after(after, limit)
+    }
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+}
+
+// A request the engine is stuck on is answered with an error, the others still go through.
+#[test]
+fn op_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4113";
+    let engine = SlowGet {
+        store: KvStore::open(temp_dir.path())?,
+        delay: Duration::from_millis(500),
+    };
+    let server = KvServer::new(engine, SharedQueueThreadPool::new(2)?, addr)?
+        .with_op_timeout(Duration::from_millis(100));
+    let handle = server.handle()?;
+    let server = thread::spawn(move || server.run());
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let started = Instant::now();
+    let err = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Engine timed out.");
+    assert!(started.elapsed() < Duration::from_millis(400));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// A set retried with the same idempotency key, even on a new connection, is applied once.
+#[test]
+fn idempotent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4114";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), "req1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    drop(client);
+    let mut client = KvClient::connect(addr)?;
+    client.set_idempotent("key1".to_owned(), "value1".to_owned(), "req1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value2"));
+    client.set_idempotent("key1".to_owned(), "value3".to_owned(), "req2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value3"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+/// `KvStore` whose writes wait for a go-ahead on `gate` and are counted in `sets`.
+#[derive(Clone)]
+struct GatedSet {
+    store: KvStore,
+    gate: Arc<Mutex<mpsc::Receiver<()>>>,
+    sets: Arc<AtomicUsize>,
+}
+
+impl KvsEngine for GatedSet {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.gate.lock().unwrap().recv().ok();
+        self.sets.fetch_add(1, Ordering::SeqCst);
+        self.store.set(key, value)
+    }
+    fn remove(&self, key: &str) -> Result<()> {
+        self.store.remove(key)
+    }
+    fn scan_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.store.scan_after(after, limit)
+    }
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+}
+
+// A retry of a set that is still running, or that timed out, is not applied a second time.
+#[test]
+fn idempotent_set_in_flight() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4132";
+    let (go, gate) = mpsc::channel();
+    let sets = Arc::new(AtomicUsize::new(0));
+    let engine = GatedSet {
+        store: KvStore::open(temp_dir.path())?,
+        gate: Arc::new(Mutex::new(gate)),
+        sets: Arc::clone(&sets),
+    };
+    let server = KvServer::new(engine, SharedQueueThreadPool::new(2)?, addr)?
+        .with_op_timeout(Duration::from_millis(100));
+    let handle = server.handle()?;
+    let server = thread::spawn(move || server.run());
+
+    let mut client = KvClient::connect(addr)?;
+    let err = client
+        .set_idempotent("key1".to_owned(), "value1".to_owned(), "req1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Engine timed out.");
+    let mut retry = KvClient::connect(addr)?;
+    let err = retry
+        .set_idempotent("key1".to_owned(), "value1".to_owned(), "req1".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Request req1 is in progress.");
+
+    go.send(()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match retry.set_idempotent("key1".to_owned(), "value1".to_owned(), "req1".to_owned()) {
+            Ok(_) => break,
+            Err(e) if e.to_string() == "Request req1 is in progress." => {
+                assert!(Instant::now() < deadline, "request never completed");
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    assert_eq!(sets.load(Ordering::SeqCst), 1);
+    assert_eq!(retry.get("key1".to_owned())?.as_deref(), Some("value1"));
+
+    drop(client);
+    drop(retry);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+/// Counts the bytes read through it.
+struct Counting<R> {
+    inner: R,
+    read: usize,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// Bytes of the answer to a get of `key`, on a connection asking for compression or not.
+fn get_size(addr: &str, key: &str, compress: bool) -> Result<usize> {
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(Counting {
+        inner: stream,
+        read: 0,
+    });
+    if compress {
+        write_message(&mut writer, &Instruction::Compress { min_size: 1024 })?;
+        let answer: Option<Response> = read_message(&mut reader)?;
+        assert!(matches!(answer, Some(Response::Ok(_))));
+    }
+    let before = reader.get_ref().read;
+    let key = key.to_owned();
+    write_message(&mut writer, &Instruction::Get { key })?;
+    let answer: Option<Response> = read_message(&mut reader)?;
+    assert!(matches!(answer, Some(Response::Ok(value)) if value.len() == 300_000));
+    Ok(reader.get_ref().read - before)
+}
+
+// Large values cross the socket gzipped once asked, and round-trip both ways.
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4115";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let value = "value".repeat(60_000);
+    let mut client = KvClient::connect_with_compression(addr, 1024)?;
+    client.set("key1".to_owned(), value.clone())?;
+    assert_eq!(
+        client.get("key1".to_owned())?.as_deref(),
+        Some(value.as_str())
+    );
+    client.set("key2".to_owned(), "small".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?.as_deref(), Some("small"));
+
+    let plain = get_size(addr, "key1", false)?;
+    let compressed = get_size(addr, "key1", true)?;
+    assert!(plain > value.len());
+    assert!(compressed * 10 < plain);
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// Compressed requests are refused until the client authenticated and asked for compression.
+#[test]
+fn compressed_request_before_negotiation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4131";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?.with_auth_token("secret"))?;
+    let get = Instruction::Get {
+        key: "key1".to_owned(),
+    };
+    let auth = Instruction::Auth {
+        token: "secret".to_owned(),
+    };
+
+    for steps in [vec![], vec![auth.clone()], vec![auth.clone(), get.clone()]] {
+        let stream = TcpStream::connect(addr)?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        for ins in steps {
+            write_message(&mut writer, &ins)?;
+            let answer: Option<Response> = read_message(&mut reader)?;
+            assert!(matches!(
+                answer,
+                Some(Response::Ok(_) | Response::NotFound(_))
+            ));
+        }
+        write_message_compressed(&mut writer, &get, 0)?;
+        let answer = read_message::<Response>(&mut reader);
+        assert!(!matches!(answer, Ok(Some(_))), "{:?}", answer);
+    }
+
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    for ins in [auth, Instruction::Compress { min_size: 1024 }] {
+        write_message(&mut writer, &ins)?;
+        let answer: Option<Response> = read_message(&mut reader)?;
+        assert!(matches!(answer, Some(Response::Ok(_))));
+    }
+    write_message_compressed(&mut writer, &get, 0)?;
+    let answer: Option<Response> = read_message(&mut reader)?;
+    assert!(matches!(answer, Some(Response::NotFound(_))));
+
+    drop((writer, reader));
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// A compressed frame inflating past `MAX_MESSAGE_LEN` is refused.
+#[test]
+fn compressed_message_too_long() -> Result<()> {
+    let mut frame = Vec::new();
+    write_message_compressed(&mut frame, &"0".repeat(MAX_MESSAGE_LEN as usize), 0)?;
+    assert!(frame.len() < 1 << 20);
+    assert!(read_message::<String>(&mut &frame[..]).is_err());
+
+    frame.clear();
+    write_message_compressed(&mut frame, &"0".repeat(1 << 20), 0)?;
+    assert_eq!(
+        read_message::<String>(&mut &frame[..])?.map(|s| s.len()),
+        Some(1 << 20)
+    );
+    Ok(())
+}
+
+// Keys spread over both servers, each always on the one it hashes to,
+// and a third server only takes keys over without moving others.
+#[test]
+fn cluster_client() -> Result<()> {
+    let addrs = ["127.0.0.1:4116", "127.0.0.1:4117"];
+    let temp_dirs = [TempDir::new()?, TempDir::new()?];
+    let mut servers = Vec::new();
+    for (addr, temp_dir) in addrs.iter().zip(&temp_dirs) {
+        servers.push(start_server(new_server(temp_dir, addr)?)?);
+    }
+    let sockets: Vec<SocketAddr> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+
+    let mut cluster = ClusterKvClient::new(sockets.clone())?;
+    let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        cluster.set(key.clone(), format!("value of {}", key))?;
+    }
+    for (addr, socket) in addrs.iter().zip(&sockets) {
+        let held = KvClient::connect(addr)?.scan_all()?;
+        assert!(held.len() > 50, "{} holds {} keys", addr, held.len());
+        assert!(held.iter().all(|(key, _)| cluster.node_of(key) == *socket));
+    }
+    let mut other = ClusterKvClient::new(sockets.clone())?;
+    assert_eq!(
+        other.get("key42".to_owned())?.as_deref(),
+        Some("value of key42")
+    );
+    other.remove("key42".to_owned())?;
+    assert_eq!(cluster.scan_all()?.len(), keys.len() - 1);
+
+    let new_node: SocketAddr = "127.0.0.1:4118".parse().unwrap();
+    let mut grown = sockets.clone();
+    grown.push(new_node);
+    let grown = ClusterKvClient::new(grown)?;
+    let moved: Vec<_> = keys
+        .iter()
+        .filter(|key| grown.node_of(key) != cluster.node_of(key))
+        .collect();
+    assert!(!moved.is_empty() && moved.len() < keys.len() / 2);
+    assert!(moved.iter().all(|key| grown.node_of(key) == new_node));
+
+    drop((cluster, other));
+    for (handle, server) in servers {
+        handle.shutdown();
+        server.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Writes are rejected by a read-only server, reads still served.
+#[test]
+fn read_only_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4119";
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.close()?;
+    let server = KvServer::new(
+        KvStore::open_read_only(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?
+    .with_read_only(true);
+    let (handle, server) = start_server(server)?;
+
+    let mut client = KvClient::connect(addr)?;
+    let err = client
+        .set("key1".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "server is read-only");
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value1"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// A missing key is `None` for get and a typed error for remove.
+#[test]
+fn missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4120";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    let err = client.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(KvError::KeyNotFound(key)) if key == "key1"
+    ));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// The length is in bytes of UTF-8, whichever way the store keeps the value.
+#[test]
+fn value_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4121";
+    let config = KvStoreConfig {
+        value_log_threshold: Some(64),
+        chunk_size: Some(16),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let server = KvServer::new(store, SharedQueueThreadPool::new(2)?, addr)?;
+    let (handle, server) = start_server(server)?;
+
+    let mut client = KvClient::connect(addr)?;
+    for value in [
+        "value",
+        "h\u{e9}ll\u{f6} w\u{f6}rld \u{2713}",
+        &"\u{1f980}".repeat(40),
+    ] {
+        client.set("key1".to_owned(), value.to_owned())?;
+        assert_eq!(client.value_len("key1".to_owned())?, Some(value.len()));
+    }
+    assert_eq!(client.value_len("key2".to_owned())?, None);
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// Each value of a multi-get is at the position of its key.
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4123";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    let keys = ["key3", "key2", "key1", "key3"];
+    let values = client.get_many(keys.iter().map(|key| key.to_string()).collect())?;
+    assert_eq!(
+        values,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            Some("value1".to_owned()),
+            Some("value3".to_owned())
+        ]
+    );
+    assert_eq!(client.get_many(Vec::new())?, Vec::<Option<String>>::new());
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// Legacy and length-prefixed clients share one server, each answered in its own framing.
+#[test]
+fn framing_per_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4124";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut legacy = KvClient::connect(addr)?;
+    let mut lines = KvClient::connect_with_framing(addr, Framing::Lines)?;
+    let mut framed = KvClient::connect_with_framing(addr, Framing::LengthPrefixed)?;
+    legacy.set("key1".to_owned(), "value1".to_owned())?;
+    lines.set("key2".to_owned(), "value2".to_owned())?;
+    framed.set("key3".to_owned(), "value\n3".to_owned())?;
+    for client in [&mut legacy, &mut lines, &mut framed] {
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(client.get("key3".to_owned())?, Some("value\n3".to_owned()));
+    }
+    assert_eq!(framed.scan_all()?.len(), 3);
+
+    // On the wire: the prefix, then the length of each message ahead of its JSON.
+    let mut raw = TcpStream::connect(addr)?;
+    let json = serde_json::to_vec(&Instruction::Get {
+        key: "key1".to_owned(),
+    })?;
+    raw.write_all(&[LENGTH_PREFIX])?;
+    raw.write_all(&(json.len() as u32).to_be_bytes())?;
+    raw.write_all(&json)?;
+    let mut len = [0; 4];
+    raw.read_exact(&mut len)?;
+    let mut reply = vec![0; u32::from_be_bytes(len) as usize];
+    raw.read_exact(&mut reply)?;
+    let reply: Response = serde_json::from_slice(&reply)?;
+    assert!(matches!(reply, Response::Ok(value) if value == "value1"));
+
+    drop((legacy, lines, framed, raw));
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// A batch removal tells which keys existed and leaves the others alone,
+// the keys left are paged through.
+#[test]
+fn remove_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4125";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let mut client = KvClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    let keys = ["key1", "missing", "key2"];
+    let removed = client.remove_many(keys.iter().map(|key| key.to_string()).collect())?;
+    assert_eq!(removed, vec![true, false, true]);
+    let keys = keys.iter().map(|key| key.to_string()).collect();
+    assert_eq!(client.get_many(keys)?, vec![None, None, None]);
+
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    client.set("key4".to_owned(), "value4".to_owned())?;
+    let (page, next) = client.keys_paginated(None, 1)?;
+    assert_eq!(
+        (page, next.clone()),
+        (vec!["key3".to_owned()], Some("key3".to_owned()))
+    );
+    let page = client.keys_paginated(next, 1)?;
+    assert_eq!(page, (vec!["key4".to_owned()], None));
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// The client tries each address in turn, an address resolving to nothing is reported.
+#[test]
+fn resolved_addresses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4127";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let addrs: [SocketAddr; 2] = ["127.0.0.1:4126".parse()?, addr.parse()?];
+    let mut client = KvClient::connect(&addrs[..])?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let nowhere: &[SocketAddr] = &[];
+    let err = KvClient::connect(nowhere).err().unwrap();
+    assert_eq!(err.to_string(), "[] resolves to no socket address.");
+    let err = KvClient::connect("no port here").err().unwrap();
+    assert_eq!(err.to_string(), "Failed to resolve \"no port here\".");
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// A traced request is answered with its id, the client checks it got its own back.
+#[test]
+fn request_id_echoed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4128";
+    let (handle, server) = start_server(new_server(&temp_dir, addr)?)?;
+
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let traced = Instruction::Traced {
+        request_id: "request-1".to_owned(),
+        instruction: Box::new(Instruction::Get {
+            key: "key1".to_owned(),
+        }),
+    };
+    write_message(&mut writer, &traced)?;
+    let reply: Option<Response> = read_message(&mut reader)?;
+    assert!(matches!(
+        reply,
+        Some(Response::Traced { request_id, response })
+            if request_id == "request-1" && matches!(*response, Response::NotFound(_))
+    ));
+
+    let mut client = KvClient::connect(addr)?;
+    assert_eq!(client.last_request_id(), None);
+    client.trace_requests(true);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let first = client.last_request_id().map(str::to_owned);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(first.is_some());
+    assert_ne!(client.last_request_id(), first.as_deref());
+
+    drop((writer, reader, client));
+    handle.shutdown();
+    server.join().unwrap()
+}
+
+// The client reconnects once the server restarts, sending again only what can't apply twice.
+#[test]
+fn client_reconnects_after_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4129";
+    let restart = |(handle, server): (ServerHandle, JoinHandle<Result<()>>)| {
+        handle.shutdown();
+        server.join().unwrap()?;
+        start_server(new_server(&temp_dir, addr)?.with_auth_token("secret"))
+    };
+    let running = start_server(new_server(&temp_dir, addr)?.with_auth_token("secret"))?;
+
+    let mut client = KvClient::connect_with_token(addr, "secret")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let running = restart(running)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let running = restart(running)?;
+    client.set_idempotent("key2".to_owned(), "value2".to_owned(), "set-1".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Not sent again, but the client is connected for the next request.
+    let (handle, server) = restart(running)?;
+    assert!(client.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert_eq!(client.get("key3".to_owned())?, None);
+
+    drop(client);
+    handle.shutdown();
+    server.join().unwrap()
+}
@@ -0,0 +1,65 @@
+//! In a binary of its own, as the allocator counts the allocations of every thread.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tempfile::TempDir;
+
+use kvs::engine::{KvStore, KvStoreConfig};
+use kvs::{KvsEngine, Result};
+
+/// Counts the allocations.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Allocations taken by `set` on each of `pairs`.
+fn allocations(pairs: Vec<(String, String)>, set: impl Fn(String, String) -> Result<()>) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for (key, value) in pairs {
+        set(key, value).unwrap();
+    }
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+// Handing the pairs over saves copying them into the record and the index.
+#[test]
+fn set_owned_allocates_less() -> Result<()> {
+    const KEYS: usize = 1000;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: Some(usize::MAX),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let pairs: Vec<_> = (0..KEYS)
+        .map(|i| (format!("key{:04}", i), "v".repeat(100)))
+        .collect();
+    // Both overwrite, so neither grows the index.
+    for (key, value) in &pairs {
+        store.set(key, value)?;
+    }
+    let borrowed = allocations(pairs.clone(), |key, value| store.set(&key, &value));
+    let owned = allocations(pairs, |key, value| store.set_owned(key, value));
+    // At least one copy of the key and one of the value saved per pair.
+    assert!(
+        owned + 2 * KEYS <= borrowed,
+        "{} allocations with set_owned, {} with set",
+        owned,
+        borrowed
+    );
+    Ok(())
+}
@@ -1,12 +1,15 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
 use std::process::Command;
 
 use assert_cmd::prelude::*;
 use predicates::ord::eq;
-use predicates::str::{contains, is_empty, PredicateStrExt};
+use predicates::prelude::*;
+use predicates::str::{contains, is_empty, is_match, PredicateStrExt};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::engine::KvStore;
+use kvs::engine::{KvStore, KvStoreConfig};
 use kvs::{KvsEngine, Result};
 
 // `kvs` with no args should exit with a non-zero code.
@@ -120,6 +123,162 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs verify` reports where a log was corrupted and exits with non-zero.
+#[test]
+fn cli_verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(&format!("key{}", i), "value")?;
+    }
+    // Saves the index, so it is checked too.
+    store.compact()?;
+    let (file_id, offset, _) = store.dump_commands()?[1].clone();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("0 problem(s) found."));
+
+    let mut log = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join(format!("{:05}.log", file_id)))?;
+    log.seek(SeekFrom::Start(offset + 2))?;
+    log.write_all(b"garbage")?;
+    drop(log);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(contains(format!(
+            "Corrupt record in log file {} at offset {}",
+            file_id, offset
+        )))
+        .stdout(contains("Index entry of key key2"));
+    Ok(())
+}
+
+// `kvs load` inserts every pair of a JSON-lines file.
+#[test]
+fn cli_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs: String = (0..2500)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"value{}\"}}\n", i, i))
+        .collect();
+    fs::write(temp_dir.path().join("pairs.jsonl"), pairs)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["load", "pairs.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Loaded 2500 pairs"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2500 {
+        assert_eq!(
+            store.get(&format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// `kvs segments` prints a row per log file.
+#[test]
+fn cli_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["segments"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_match(r"file +bytes +live +dead\n +0 +\d+ +1 +1\n$").unwrap());
+    Ok(())
+}
+
+// `kvs hotkeys` lists the counts saved by the last store sampling reads.
+#[test]
+fn cli_hotkeys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        sample_reads: Some(1),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key in ["key1", "key2", "key3"] {
+        store.set(key, "value")?;
+    }
+    for _ in 0..5 {
+        store.get("key2")?;
+    }
+    store.get("key3")?;
+    store.close()?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["hotkeys", "-n", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_match(r"^ +5 key2\n +1 key3\n$").unwrap());
+    Ok(())
+}
+
+// `kvs --format json` should print one JSON object per invocation.
+#[test]
+fn cli_json_format() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"ok":true}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--format", "json", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key1","value":"value1"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key2", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"found":false,"key":"key2"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "key2", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(r#""ok":false"#).and(contains("Key: key2 not found")));
+
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
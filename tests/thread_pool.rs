@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread;
+use std::time::Duration;
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -69,3 +71,57 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+fn worker_names<P: ThreadPool>() -> Result<()> {
+    let config = PoolConfig {
+        thread_name_prefix: Some("test-worker-".to_owned()),
+        ..PoolConfig::new(2)
+    };
+    let pool = P::new_with_config(config)?;
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..4 {
+        let tx = tx.clone();
+        pool.spawn(move || {
+            let name = thread::current().name().map(str::to_owned);
+            tx.send(name).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        let name = rx.recv()?.unwrap();
+        assert!(name.starts_with("test-worker-"), "{}", name);
+    }
+    Ok(())
+}
+
+#[test]
+fn thread_name_prefix() -> Result<()> {
+    worker_names::<NaiveThreadPool>()?;
+    worker_names::<SharedQueueThreadPool>()?;
+    worker_names::<RayonThreadPool>()
+}
+
+// Panicking jobs leave the pool with all its workers, two jobs still run side by side.
+#[test]
+fn panic_policy_keeps_workers() -> Result<()> {
+    let pool = SharedQueueThreadPool::new_with_config(PoolConfig::new(2))?;
+    for _ in 0..4 {
+        pool.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        });
+    }
+    let barrier = Arc::new(Barrier::new(2));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..2 {
+        let barrier = barrier.clone();
+        let tx = tx.clone();
+        pool.spawn(move || {
+            barrier.wait();
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..2 {
+        rx.recv_timeout(Duration::from_secs(10))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+#![cfg(feature = "tls")]
+
+use std::fs;
+use std::thread;
+
+use tempfile::TempDir;
+
+use kvs::engine::KvStore;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvClient, KvServer, Result};
+
+// A set/get round trip over TLS with a self-signed certificate.
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let (cert_path, key_path) = (
+        temp_dir.path().join("cert.pem"),
+        temp_dir.path().join("key.pem"),
+    );
+    fs::write(&cert_path, cert.serialize_pem()?)?;
+    fs::write(&key_path, cert.serialize_private_key_pem())?;
+
+    let addr = "127.0.0.1:4100";
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path().join("data"))?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?
+    .with_tls(&cert_path, &key_path)?;
+    let handle = server.handle()?;
+    let server_thread = thread::spawn(move || server.run());
+
+    let mut client = KvClient::connect_tls(addr, "localhost", Some(cert_path.as_path()))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?.as_deref(), Some("value1"));
+
+    // The plaintext client can't talk to a TLS server.
+    let mut plain = KvClient::connect(addr)?;
+    assert!(plain.get("key1".to_owned()).is_err());
+
+    drop(client);
+    drop(plain);
+    handle.shutdown();
+    server_thread.join().unwrap()
+}